@@ -0,0 +1,61 @@
+use std::path::PathBuf;
+use std::str::FromStr;
+
+use log::LevelFilter;
+
+pub const USAGE: &str = "Usage: nexus-drv-io [OPTIONS]
+
+Options:
+  --config <path>        Config file (default: /etc/nexus-drv-io/config.toml)
+  --socket-path <path>   UDS socket of the driver API
+  --log-level <level>    off, error, warn, info, debug or trace
+  --foreground           Log to stderr and do not notify systemd
+  --no-systemd           Same as --foreground
+  --check-config         Validate the config file and exit
+  --version              Print the driver version and the required SDBPK version
+  --help                 Print this help";
+
+/// Command line arguments of the driver.
+#[derive(Debug, Default)]
+pub struct Cli {
+    pub config: Option<PathBuf>,
+    pub socket_path: Option<String>,
+    pub log_level: Option<LevelFilter>,
+    pub foreground: bool,
+    pub check_config: bool,
+    pub version: bool,
+    pub help: bool,
+}
+
+impl Cli {
+    pub fn parse<I: Iterator<Item = String>>(mut args: I) -> Result<Cli, String> {
+        let mut cli = Cli::default();
+
+        while let Some(arg) = args.next() {
+            match arg.as_str() {
+                "--config" => cli.config = Some(PathBuf::from(Cli::value(&arg, args.next())?)),
+                "--socket-path" => cli.socket_path = Some(Cli::value(&arg, args.next())?),
+                "--log-level" => {
+                    let value = Cli::value(&arg, args.next())?;
+                    match LevelFilter::from_str(&value) {
+                        Ok(level) => cli.log_level = Some(level),
+                        Err(_) => return Err(format!("Invalid log level: {}", value)),
+                    }
+                }
+                "--foreground" | "--no-systemd" => cli.foreground = true,
+                "--check-config" => cli.check_config = true,
+                "--version" | "-V" => cli.version = true,
+                "--help" | "-h" => cli.help = true,
+                _ => return Err(format!("Unknown argument: {}", arg)),
+            }
+        }
+        Ok(cli)
+    }
+
+    fn value(arg: &str, value: Option<String>) -> Result<String, String> {
+        match value {
+            Some(value) => Ok(value),
+            None => Err(format!("Missing value for {}", arg)),
+        }
+    }
+}
@@ -1,6 +1,12 @@
+#[macro_use]
+extern crate log;
+
 use std::time::Duration;
 
+pub mod powermgmt;
 pub mod settings;
+pub mod status;
+pub mod watchdog;
 
 use noreya_sdbp::datatypes::*;
 use std::thread::sleep;
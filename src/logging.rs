@@ -0,0 +1,27 @@
+use log::{LevelFilter, Log, Metadata, Record};
+
+/// Logger for running outside of systemd.
+struct StderrLogger;
+
+static LOGGER: StderrLogger = StderrLogger;
+
+impl Log for StderrLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if self.enabled(record.metadata()) {
+            eprintln!("[{:<5} {}] {}", record.level(), record.target(), record.args());
+        }
+    }
+
+    fn flush(&self) {}
+}
+
+pub fn init_stderr_logger() {
+    match log::set_logger(&LOGGER) {
+        Ok(_) => log::set_max_level(LevelFilter::Info),
+        Err(err) => eprintln!("Could not set logger: {}", err),
+    }
+}
@@ -2,28 +2,159 @@
 extern crate log;
 
 use std::{env, panic, process};
+use std::io::{Error, ErrorKind};
+use std::path::Path;
 use std::process::exit;
-use std::thread::sleep;
+use std::thread;
 use std::time::Duration;
 
+use log::LevelFilter;
 use noreya_sdbp::util::logging::init_systemd_logger;
 use noreya_sdbp::datatypes::*;
 use noreya_sdbp::drv::core::{Controller, DeviceFilter, DeviceHandler, Dispatcher, DrvMeta, SdbpkCheck, SharedStats, Stats, UdsServer};
 use noreya_sdbp::drv::service::service::SdbpModule;
 use sd_notify::NotifyState;
-use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
 use signal_hook::iterator::Signals;
 
-use powermgmt::PowerMgmt;
+use nexus_drv_io::{powermgmt, settings, status};
+use nexus_drv_io::powermgmt::PowerMgmt;
+use nexus_drv_io::settings::Settings;
+use nexus_drv_io::watchdog;
+use nexus_drv_io::watchdog::Watchdog;
 
-use crate::settings::{COMPATIBLE_FW_MAJOR, COMPATIBLE_FW_MINOR};
+use crate::cli::{Cli, USAGE};
 
-pub mod settings;
-pub mod powermgmt;
+pub mod cli;
+pub mod logging;
+
+fn sdbpk_check() -> SdbpkCheck {
+    SdbpkCheck {
+        major: 1,
+        minor: 3,
+        patch: 0
+    }
+}
+
+/// Time a thread gets on top of its own stop timeout before it is reported as hanging
+const STOP_GRACE: Duration = Duration::from_millis(500);
+/// Exit code if not all threads stopped within their deadline
+const EXIT_STOP_FAILED: i32 = 3;
+
+/// Stops a thread via `stop` and waits at most `deadline` for it to return.
+fn stop_thread<F: FnOnce() + Send + 'static>(name: &str, deadline: Duration, stop: F) -> bool {
+    let (tx, rx) = crossbeam_channel::bounded(1);
+    let spawned = thread::Builder::new().name(format!("stop {}", name)).spawn(move || {
+        stop();
+        let _ = tx.send(());
+    });
+    if let Err(err) = spawned {
+        error!("Could not stop {}: {}", name, err);
+        return false;
+    }
+
+    match rx.recv_timeout(deadline) {
+        Ok(_) => true,
+        Err(_) => {
+            error!("{} did not stop within {:?}", name, deadline);
+            false
+        }
+    }
+}
+
+/// Loads the config file and applies the command line overrides.
+fn load_config(cli: &Cli, path: &Path, required: bool, default_level: LevelFilter) -> Result<Settings, Error> {
+    let mut config = match Settings::load(path, required) {
+        Ok(value) => value,
+        Err(err) => return Err(err),
+    };
+    if let Some(socket_path) = &cli.socket_path {
+        config.socket_path = socket_path.clone();
+        match config.validate() {
+            Ok(_) => (),
+            Err(err) => return Err(Error::new(ErrorKind::InvalidInput, err)),
+        }
+    }
+    // Without a level the logger returns to its default, also after a reload
+    log::set_max_level(cli.log_level.or(config.log_level()).unwrap_or(default_level));
+    Ok(config)
+}
+
+/// Reloads the config file, settings which are only used at startup keep their running value.
+fn reload(cli: &Cli, path: &Path, required: bool, default_level: LevelFilter) {
+    status::notify(&[NotifyState::Reloading]);
+    info!("Reloading config {}", path.display());
+
+    let running = settings::current();
+    match load_config(cli, path, required, default_level) {
+        Ok(mut config) => {
+            if config.module_name != running.module_name || config.socket_path != running.socket_path ||
+                config.compatible_fw_major != running.compatible_fw_major || config.compatible_fw_minor != running.compatible_fw_minor {
+                warn!("Changes of module_name, socket_path and compatible_fw_* require a restart");
+                config.module_name = running.module_name.clone();
+                config.socket_path = running.socket_path.clone();
+                config.compatible_fw_major = running.compatible_fw_major;
+                config.compatible_fw_minor = running.compatible_fw_minor;
+            }
+            settings::install(config);
+            powermgmt::request_reload();
+            info!("Config reloaded");
+            status::set("Config reloaded, waiting for requests...");
+        }
+        Err(err) => {
+            error!("Reloading config failed, keeping the running config: {}", err);
+            status::set("Config reload failed, waiting for requests...");
+        }
+    }
+    status::notify(&[NotifyState::Ready]);
+}
 
 fn main() {
-    init_systemd_logger();
     let version = env!("CARGO_PKG_VERSION");
+    let cli = match Cli::parse(env::args().skip(1)) {
+        Ok(value) => value,
+        Err(err) => {
+            eprintln!("{}\n\n{}", err, USAGE);
+            exit(2)
+        }
+    };
+
+    if cli.help {
+        println!("{}", USAGE);
+        return;
+    }
+    if cli.version {
+        let check = sdbpk_check();
+        println!("{} {}", settings::DRV_NAME, version);
+        println!("Requires SDBPK kernel driver >= {}.{}.{}", check.major, check.minor, check.patch);
+        return;
+    }
+
+    let systemd = !cli.foreground;
+    match systemd {
+        true => {
+            init_systemd_logger();
+            status::enable_systemd();
+        }
+        false => logging::init_stderr_logger(),
+    }
+    let default_level = log::max_level();
+
+    let (config_path, required) = Settings::path(cli.config.clone());
+    let config = match load_config(&cli, &config_path, required, default_level) {
+        Ok(value) => value,
+        Err(err) => {
+            error!("{}",err);
+            exit(-1)
+        }
+    };
+
+    if cli.check_config {
+        println!("Config {} is valid", config_path.display());
+        return;
+    }
+    settings::install(config.clone());
+
     let orig_hook = panic::take_hook();
     panic::set_hook(Box::new(move |panic_info| {
         // This ends the entire process if one thread panics
@@ -31,13 +162,7 @@ fn main() {
         process::exit(1);
     }));
 
-    let check = SdbpkCheck {
-        major: 1,
-        minor: 3,
-        patch: 0
-    };
-
-    let check = match check.check_version() {
+    let check = match sdbpk_check().check_version() {
         Ok(version) => {
             info!("SDBPK driver version: {}.{}.{}", version.major, version.minor, version.patch);
             version
@@ -50,14 +175,14 @@ fn main() {
 
     info!("Module driver version: {}",version);
 
-    let mut signals = Signals::new(&[SIGTERM,SIGINT]).ok().unwrap();
+    let mut signals = Signals::new(&[SIGTERM,SIGINT,SIGHUP]).ok().unwrap();
     let mut filter  = DeviceFilter::<String>::new();
-    filter.add(settings::MODULE_NAME.to_string());
+    filter.add(config.module_name.clone());
 
     /*
      * Prepare Global Settings
      */
-    let shared = SharedStats::new(Stats::new(settings::MODULE_NAME.to_string(),Version::from_str(version).unwrap(),check.to_version()));
+    let shared = SharedStats::new(Stats::new(config.module_name.clone(),Version::from_str(version).unwrap(),check.to_version()));
 
     /*
      * Device-Event channels
@@ -66,28 +191,77 @@ fn main() {
 
     let device_handler = DeviceHandler::start(filter,devt_receiver.clone(),devt_sender.clone());
     let dispatcher = Dispatcher::start();
-    let controller = Controller::start(dispatcher.get_com(), devt_receiver.clone(), shared.clone(), SdbpModule::handle_function, COMPATIBLE_FW_MAJOR, COMPATIBLE_FW_MINOR);
+    let controller = Controller::start(dispatcher.get_com(), devt_receiver.clone(), shared.clone(), SdbpModule::handle_function, config.compatible_fw_major, config.compatible_fw_minor);
 
-    let meta = DrvMeta::new(settings::MODULE_NAME.to_string(),settings::DRV_NAME.to_string(),settings::SOCKET_PATH.to_string());
+    let meta = DrvMeta::new(config.module_name.clone(),settings::DRV_NAME.to_string(),config.socket_path.clone());
     let udsserver = UdsServer::start(meta,dispatcher.get_com(),shared.clone());
 
-    let power_mgmt = Controller::start_virtual_device("PowerMgmt".to_string(), 0x2001, &mut dispatcher.get_com(), shared.clone(), PowerMgmt::handle_function);
+    let power_mgmt = Controller::start_virtual_device("PowerMgmt".to_string(), powermgmt::VDEV_ID, &mut dispatcher.get_com(), shared.clone(), PowerMgmt::handle_function);
 
-    info!("Started driver for {}",settings::MODULE_NAME);
-    let _ = sd_notify::notify(false, &[NotifyState::Ready]);
-    let _ = sd_notify::notify(false, &[NotifyState::Status("Waiting for requests...")]);
+    let watchdog = match systemd {
+        true => Watchdog::start(shared.clone()),
+        false => None,
+    };
+    let probe = match watchdog {
+        Some(_) => Some(Controller::start_virtual_device("Watchdog".to_string(), watchdog::PROBE_VDEV_ID, &mut dispatcher.get_com(), shared.clone(), watchdog::probe_function)),
+        None => None,
+    };
 
-    for _sig in signals.forever() {
-        udsserver.stop(Duration::from_millis(100)); // Note: duration must be low for udsserver
-        device_handler.stop(Duration::from_millis(1000));
-        controller.stop(Duration::from_millis(1000));
-        dispatcher.stop(Duration::from_millis(1000));
-        power_mgmt.stop(Duration::from_millis(1000));
-        break;
+    info!("Started driver for {}",config.module_name);
+    status::notify(&[NotifyState::Ready]);
+    status::set("Waiting for requests...");
+
+    for sig in signals.forever() {
+        match sig {
+            SIGHUP => reload(&cli, &config_path, required, default_level),
+            _ => break,
+        }
+    }
+
+    status::notify(&[NotifyState::Stopping]);
+    if let Some(watchdog) = watchdog {
+        watchdog.stop();
+    }
+
+    /*
+     * Stop order: watchdog probe, no new clients, finish running power config transactions
+     * (needs controller and dispatcher for the module replies), then the device side.
+     */
+    let current = settings::current();
+    let timeouts = &current.timeouts;
+    let mut failed: Vec<&str> = Vec::new();
+
+    let thread_stop = timeouts.thread_stop();
+    if let Some(probe) = probe {
+        if !stop_thread("Watchdog", thread_stop + STOP_GRACE, move || { probe.stop(thread_stop); }) {
+            failed.push("Watchdog");
+        }
+    }
+    let uds_stop = timeouts.uds_stop(); // Note: duration must be low for udsserver
+    if !stop_thread("UdsServer", uds_stop + STOP_GRACE, move || { udsserver.stop(uds_stop); }) {
+        failed.push("UdsServer");
+    }
+    let drain = timeouts.drain();
+    if !stop_thread("PowerMgmt", drain + STOP_GRACE, move || { power_mgmt.stop(drain); }) || !powermgmt::is_stopped() {
+        failed.push("PowerMgmt");
+    }
+    if !stop_thread("DeviceHandler", thread_stop + STOP_GRACE, move || { device_handler.stop(thread_stop); }) {
+        failed.push("DeviceHandler");
+    }
+    if !stop_thread("Controller", thread_stop + STOP_GRACE, move || { controller.stop(thread_stop); }) {
+        failed.push("Controller");
+    }
+    if !stop_thread("Dispatcher", thread_stop + STOP_GRACE, move || { dispatcher.stop(thread_stop); }) {
+        failed.push("Dispatcher");
     }
 
-    let _ = sd_notify::notify(false, &[NotifyState::Stopping]);
-    sleep(Duration::from_secs(3)); // Wait some time to let all the threads stop...
-    let _ = sd_notify::notify(false, &[NotifyState::Status("Service stopped successfully")]);
-    info!("Driver service stopped")
+    if failed.is_empty() {
+        status::set("Service stopped successfully");
+        info!("Driver service stopped");
+    } else {
+        let message = format!("Service stopped, threads not responding: {}", failed.join(", "));
+        status::set(&message);
+        error!("{}", message);
+        exit(EXIT_STOP_FAILED);
+    }
 }
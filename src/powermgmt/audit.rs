@@ -0,0 +1,159 @@
+use std::fs::{self, OpenOptions};
+use std::io::{Error, ErrorKind, Write};
+use std::path::PathBuf;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde::Serialize;
+
+use crate::powermgmt::data::PowerConfig;
+use crate::powermgmt::error::PowerMgmtError;
+use crate::settings::{self, Audit};
+
+#[derive(Serialize)]
+pub struct AuditPin {
+    pub voltage: u8,
+    pub current_ma: u16,
+}
+
+/// Pin config of a slot before and after a request.
+#[derive(Serialize)]
+pub struct AuditSlot {
+    pub slot: u8,
+    /// `None` if the driver did not apply a config to the slot before
+    pub old: Option<Vec<AuditPin>>,
+    pub new: Vec<AuditPin>,
+}
+
+impl AuditSlot {
+    pub fn new(old: Option<&PowerConfig>, new: &PowerConfig) -> AuditSlot {
+        AuditSlot {
+            slot: new.get_device_id(),
+            old: old.map(pins),
+            new: pins(new),
+        }
+    }
+}
+
+fn pins(config: &PowerConfig) -> Vec<AuditPin> {
+    config.pin_vec().into_iter().map(|(voltage, current_ma)| AuditPin { voltage, current_ma }).collect()
+}
+
+/// One line of the audit log.
+#[derive(Serialize)]
+pub struct AuditRecord {
+    /// Milliseconds since the Unix epoch
+    pub timestamp_ms: u64,
+    /// Device id of the requesting UDS client, `None` for configs the driver restored itself
+    ///
+    /// The UdsServer of noreya_sdbp hands requests over as `PMsg` with the dispatcher device id of the
+    /// connection only, the peer credentials (uid, gid, pid) of the socket are not available to the driver.
+    pub client: Option<u16>,
+    pub command: &'static str,
+    pub slots: Vec<AuditSlot>,
+    /// `applied`, `budget_exceeded` or `failed`
+    pub result: &'static str,
+    /// Power exceeding the budget per rail in mW as reported by power-mgmt
+    pub shortfall: Option<[u16; 3]>,
+    pub error_code: Option<u16>,
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    /// `result` is the response starting with the shortfall per rail or the error of the request.
+    pub fn new(client: Option<u16>, command: &'static str, slots: Vec<AuditSlot>, result: Result<&[u8], &Error>) -> AuditRecord {
+        let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).map(|time| time.as_millis() as u64).unwrap_or(0);
+        let mut record = AuditRecord { timestamp_ms, client, command, slots, result: "applied", shortfall: None, error_code: None, error: None };
+
+        match result {
+            Ok([a, b, c, d, e, f, ..]) => {
+                let shortfall = [u16::from_be_bytes([*a, *b]), u16::from_be_bytes([*c, *d]), u16::from_be_bytes([*e, *f])];
+                if shortfall != [0, 0, 0] {
+                    record.result = "budget_exceeded";
+                    record.shortfall = Some(shortfall);
+                }
+            }
+            Ok(_) => (),
+            Err(err) => {
+                let err = PowerMgmtError::from_io(err);
+                if let PowerMgmtError::BudgetExceeded { shortfall, .. } = err {
+                    record.result = "budget_exceeded";
+                    record.shortfall = Some([shortfall.0, shortfall.1, shortfall.2]);
+                    return record;
+                }
+                record.result = "failed";
+                record.error_code = Some(err.code());
+                record.error = Some(err.to_string());
+            }
+        }
+        record
+    }
+}
+
+/// Append-only JSON lines log, rotated by size.
+///
+/// Only power config changes of the PowerMgmt device are logged, output changes are not audited.
+pub struct AuditLog {
+    dir: PathBuf,
+    max_size: u64,
+    keep: usize,
+}
+
+impl AuditLog {
+    pub fn new(audit: &Audit) -> AuditLog {
+        AuditLog {
+            dir: PathBuf::from(&audit.dir),
+            max_size: audit.max_size_kb * 1024,
+            keep: audit.keep,
+        }
+    }
+
+    /// Writes a record, failures are logged but never fail the request.
+    pub fn append(&self, record: &AuditRecord) {
+        let line = match serde_json::to_string(record) {
+            Ok(value) => value,
+            Err(err) => {
+                error!("Could not serialize audit record: {}", err);
+                return;
+            }
+        };
+        match self.write(&line) {
+            Ok(_) => (),
+            Err(err) => error!("Could not write audit log in {}: {}", self.dir.display(), err),
+        }
+    }
+
+    fn write(&self, line: &str) -> Result<(), Error> {
+        let path = self.dir.join(settings::AUDIT_FILE);
+        match fs::metadata(&path) {
+            Ok(meta) if meta.len() + line.len() as u64 + 1 > self.max_size => match self.rotate() {
+                Ok(_) => (),
+                Err(err) => return Err(err),
+            },
+            _ => (),
+        }
+
+        let mut file = match OpenOptions::new().create(true).append(true).open(&path) {
+            Ok(value) => value,
+            Err(err) => return Err(err),
+        };
+        writeln!(file, "{}", line)
+    }
+
+    /// Shifts `audit.log.N` to `audit.log.N+1`, the oldest log is dropped.
+    fn rotate(&self) -> Result<(), Error> {
+        let rotated = |index: usize| self.dir.join(format!("{}.{}", settings::AUDIT_FILE, index));
+        let path = self.dir.join(settings::AUDIT_FILE);
+        if self.keep == 0 {
+            return fs::remove_file(path);
+        }
+
+        for index in (1..self.keep).rev() {
+            match fs::rename(rotated(index), rotated(index + 1)) {
+                Ok(_) => (),
+                Err(err) if err.kind() == ErrorKind::NotFound => (),
+                Err(err) => return Err(err),
+            }
+        }
+        fs::rename(path, rotated(1))
+    }
+}
@@ -0,0 +1,314 @@
+use std::fmt;
+use std::fs;
+use std::io::Error;
+use std::os::unix::fs::MetadataExt;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use noreya_sdbp::powermgmt::manager::PowerManager;
+
+use crate::powermgmt::error::PowerMgmtError;
+use crate::powermgmt::retry;
+use crate::settings;
+use crate::status;
+
+const BACKOFF_START: Duration = Duration::from_millis(100);
+const BACKOFF_MAX: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Health {
+    /// No connection attempt yet
+    Unknown,
+    Connected,
+    Unreachable { failures: u32 },
+}
+
+impl fmt::Display for Health {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Health::Unknown => write!(f, "not connected yet"),
+            Health::Connected => write!(f, "connected"),
+            Health::Unreachable { failures } => write!(f, "unreachable ({} failed attempts)", failures),
+        }
+    }
+}
+
+impl Health {
+    /// Encodes the health as state (0 = not connected yet, 1 = connected, 2 = unreachable)
+    /// followed by the failed attempts as big-endian u32.
+    pub fn to_wire(self) -> Vec<u8> {
+        let (state, failures): (u8, u32) = match self {
+            Health::Unknown => (0, 0),
+            Health::Connected => (1, 0),
+            Health::Unreachable { failures } => (2, failures),
+        };
+        let mut wire = vec![state];
+        wire.extend(failures.to_be_bytes());
+        wire
+    }
+}
+
+/// Power exceeding the available budget per rail (3V3, 5V, 12V) in mW
+pub type Shortfall = (u16, u16, u16);
+
+/// Connection to power-mgmt, dropping it without finishing its request abandons the reservation.
+pub trait Connection: Send {
+    /// Reserves a budget (3V3, 5V, 12V in mW), returns the shortfall if power-mgmt rejected it.
+    fn request(&mut self, slot: u8, budget: (u16, u16, u16)) -> Result<Option<Shortfall>, Error>;
+
+    /// Turns the reservation into the budget of the slot, returns false if power-mgmt refused.
+    fn finish_request(&mut self) -> Result<bool, Error>;
+}
+
+/// Opens connections to power-mgmt.
+pub trait Connector: Send {
+    fn connect(&self) -> Result<Box<dyn Connection>, Error>;
+
+    /// Identifies the running power-mgmt instance, it changes when power-mgmt restarts.
+    fn instance(&self) -> Option<(u64, u64)>;
+}
+
+/// Connects to the power-mgmt socket of the current settings, instances are told apart by the socket inode.
+pub struct SocketConnector;
+
+impl Connector for SocketConnector {
+    fn connect(&self) -> Result<Box<dyn Connection>, Error> {
+        let current = settings::current();
+        match PowerManager::new(current.power_mgmt_path.clone(), Some(current.timeouts.power_mgmt())) {
+            Ok(con_pm) => Ok(Box::new(con_pm)),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn instance(&self) -> Option<(u64, u64)> {
+        fs::metadata(&settings::current().power_mgmt_path).ok().map(|meta| (meta.dev(), meta.ino()))
+    }
+}
+
+impl Connection for PowerManager {
+    fn request(&mut self, slot: u8, budget: (u16, u16, u16)) -> Result<Option<Shortfall>, Error> {
+        match PowerManager::request(self, slot, budget.0, budget.1, budget.2) {
+            Ok(response) if response.successful => Ok(None),
+            Ok(response) => Ok(Some((response.to_much_power_3v3, response.to_much_power_5v0, response.to_much_power_12v))),
+            Err(err) => Err(PowerMgmtError::PowerMgmtUnreachable { reason: format!("Requesting budget of slot {} failed: {:?}", slot, err) }.into()),
+        }
+    }
+
+    fn finish_request(&mut self) -> Result<bool, Error> {
+        match PowerManager::finish_request(self) {
+            Ok(response) => Ok(response.successful),
+            Err(err) => Err(PowerMgmtError::PowerMgmtUnreachable { reason: format!("{:?}", err) }.into()),
+        }
+    }
+}
+
+/// Background work the PowerMgmt thread has to start for the client.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Maintenance {
+    /// Try to reach power-mgmt again
+    Reconnect,
+    /// power-mgmt restarted, the budgets held by the driver must be registered again
+    Reregister,
+}
+
+/// Long-lived client of the power-mgmt service.
+///
+/// Keeps one idle connection, reconnects with exponential backoff and detects a restart
+/// of power-mgmt by its changed instance (the recreated socket). Closing a connection without `finish_request`
+/// abandons its reservation, so a connection is only reused after it finished its request.
+pub struct PowerMgmtClient {
+    connector: Box<dyn Connector>,
+    idle: Option<Box<dyn Connection>>,
+    /// Shared with the health request, which must not wait for a running negotiation
+    health: Arc<Mutex<Health>>,
+    /// Instance of power-mgmt the last connection was made to
+    instance: Option<(u64, u64)>,
+    reregister: bool,
+    maintaining: bool,
+    backoff: Duration,
+    next_attempt: Instant,
+}
+
+impl PowerMgmtClient {
+    pub fn new(connector: Box<dyn Connector>, health: Arc<Mutex<Health>>) -> PowerMgmtClient {
+        PowerMgmtClient {
+            connector,
+            idle: None,
+            health,
+            instance: None,
+            reregister: false,
+            maintaining: false,
+            backoff: BACKOFF_START,
+            next_attempt: Instant::now(),
+        }
+    }
+
+    /// Returns a connection without open reservation.
+    pub fn connect(&mut self) -> Result<Box<dyn Connection>, Error> {
+        self.restarted();
+        if let Some(con_pm) = self.idle.take() {
+            return Ok(con_pm);
+        }
+
+        match self.connector.connect() {
+            Ok(con_pm) => {
+                self.instance = self.connector.instance();
+                self.backoff = BACKOFF_START;
+                self.set_health(Health::Connected);
+                Ok(con_pm)
+            }
+            Err(err) => {
+                self.failed();
+                Err(PowerMgmtError::PowerMgmtUnreachable { reason: err.to_string() }.into())
+            }
+        }
+    }
+
+    /// Requests a budget, returns the connection holding the reservation
+    /// and the power exceeding the budget per rail if power-mgmt rejected it.
+    ///
+    /// Attempts are retried as configured. A failed attempt drops its connection, which abandons
+    /// a reservation it may have made, so a retry cannot reserve the budget twice.
+    pub fn reserve(&mut self, slot: u8, budget: (u16, u16, u16)) -> Result<(Box<dyn Connection>, Option<Shortfall>), Error> {
+        let policy = settings::current().retry.power_mgmt.clone();
+        retry::run("power-mgmt request", slot, &policy, || {
+            let mut con_pm = match self.connect() {
+                Ok(value) => value,
+                Err(err) => return Err(err),
+            };
+            match con_pm.request(slot, budget) {
+                Ok(shortfall) => Ok((con_pm, shortfall)),
+                Err(err) => {
+                    drop(con_pm);
+                    self.failed();
+                    Err(err)
+                }
+            }
+        })
+    }
+
+    /// Hands back a connection after its request was finished.
+    pub fn release(&mut self, con_pm: Box<dyn Connection>) {
+        self.idle = Some(con_pm);
+    }
+
+    /// Records a failed request, the connection it used must be dropped.
+    pub fn failed(&mut self) {
+        self.idle = None;
+        let failures = match self.health() {
+            Health::Unreachable { failures } => failures + 1,
+            _ => 1,
+        };
+        self.next_attempt = Instant::now() + self.backoff;
+        self.backoff = (self.backoff * 2).min(BACKOFF_MAX);
+        self.set_health(Health::Unreachable { failures });
+    }
+
+    /// Returns the work to start on a worker thread, called periodically by the PowerMgmt thread.
+    pub fn maintenance(&mut self) -> Option<Maintenance> {
+        if self.maintaining {
+            return None;
+        }
+        if self.restarted() {
+            info!("power-mgmt restarted");
+        }
+
+        let due = Instant::now() >= self.next_attempt;
+        let work = match (self.reregister, self.health()) {
+            (true, _) if due => Some(Maintenance::Reregister),
+            (false, Health::Unreachable { .. }) if due => Some(Maintenance::Reconnect),
+            _ => None,
+        };
+        self.maintaining = work.is_some();
+        work
+    }
+
+    /// Ends the work returned by [`PowerMgmtClient::maintenance`], `reregistered` is set once all budgets were registered.
+    pub fn maintained(&mut self, reregistered: bool) {
+        self.maintaining = false;
+        if reregistered {
+            self.reregister = false;
+        }
+    }
+
+    /// Detects a restart of power-mgmt since the last connection.
+    fn restarted(&mut self) -> bool {
+        let known = match self.instance {
+            Some(value) => value,
+            None => return false,
+        };
+        if self.connector.instance() == Some(known) {
+            return false;
+        }
+
+        self.idle = None;
+        self.instance = None;
+        self.reregister = true;
+        true
+    }
+
+    fn health(&self) -> Health {
+        *self.health.lock().expect("Could not lock health")
+    }
+
+    fn set_health(&mut self, health: Health) {
+        let mut current = self.health.lock().expect("Could not lock health");
+        if *current == health {
+            return;
+        }
+        match health {
+            Health::Unreachable { failures: 1 } => warn!("power-mgmt unreachable"),
+            Health::Connected => info!("power-mgmt connected"),
+            _ => (),
+        }
+        *current = health;
+        status::set_power_mgmt(&health.to_string());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use super::*;
+    use crate::powermgmt::testing::{FakePowerMgmt, PowerMgmtSocket};
+
+    /// Identifies the instance by the socket stand-in, the budgets are negotiated with the fake power-mgmt.
+    struct SocketInstance(FakePowerMgmt);
+
+    impl Connector for SocketInstance {
+        fn connect(&self) -> Result<Box<dyn Connection>, Error> {
+            self.0.connect()
+        }
+
+        fn instance(&self) -> Option<(u64, u64)> {
+            SocketConnector.instance()
+        }
+    }
+
+    fn client(connector: Box<dyn Connector>) -> PowerMgmtClient {
+        PowerMgmtClient::new(connector, Arc::new(Mutex::new(Health::Unknown)))
+    }
+
+    #[test]
+    fn restart_and_outage_of_the_socket_are_detected() {
+        let mut socket = PowerMgmtSocket::start();
+        let mut client = client(Box::new(SocketInstance(FakePowerMgmt::new((1000, 1000, 1000)))));
+        let con_pm = client.connect().unwrap();
+        client.release(con_pm);
+        assert_eq!(client.maintenance(), None);
+
+        socket.restart();
+        assert_eq!(client.maintenance(), Some(Maintenance::Reregister));
+        client.maintained(false);
+
+        socket.stop();
+        let mut client = self::client(Box::new(SocketConnector));
+        let err = match client.connect() {
+            Ok(_) => panic!("Connected without power-mgmt socket"),
+            Err(err) => err,
+        };
+        assert_eq!(PowerMgmtError::from_io(&err).name(), "power_mgmt_unreachable");
+        assert_eq!(client.health(), Health::Unreachable { failures: 1 });
+    }
+}
@@ -0,0 +1,55 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+/// Reasons a PowerConfig frame can be rejected.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FrameError {
+    /// Frame is shorter than the header
+    TooShort { len: usize },
+    /// Header is not `[slot, 0x03, 0x03, command]` with a known command
+    BadHeader { header: [u8; 4] },
+    /// Payload ends inside a pin record
+    TruncatedPin { index: usize, remaining: usize },
+    /// More pin records than the module has pins
+    TooManyPins { count: usize, max: usize },
+    /// Voltage code of a pin is neither 5V nor 12V
+    UnknownVoltage { index: usize, code: u8 },
+    /// Payload attached to a command which takes none
+    UnexpectedPayload { len: usize },
+    /// Bulk payload ends inside the slot and pin count of an entry
+    TruncatedSlot { remaining: usize },
+    /// Slot appears more than once in a bulk request
+    DuplicateSlot { slot: u8 },
+    /// Bulk request without any slot
+    EmptyBulk,
+    /// Versioned request with a protocol version the driver does not know
+    UnsupportedVersion { version: u8 },
+    /// Versioned request with an unknown command
+    UnknownCommand { command: u8 },
+}
+
+impl fmt::Display for FrameError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            FrameError::TooShort { len } => write!(f, "Frame too short: {} bytes", len),
+            FrameError::BadHeader { header } => write!(f, "Wrong frame header: {:?}", header),
+            FrameError::TruncatedPin { index, remaining } => write!(f, "Pin {} truncated: {} of 3 bytes", index, remaining),
+            FrameError::TooManyPins { count, max } => write!(f, "Too many pins: {} (max {})", count, max),
+            FrameError::UnknownVoltage { index, code } => write!(f, "Pin {} has unknown voltage code {}", index, code),
+            FrameError::UnexpectedPayload { len } => write!(f, "Unexpected payload of {} bytes", len),
+            FrameError::TruncatedSlot { remaining } => write!(f, "Slot entry truncated: {} of 2 bytes", remaining),
+            FrameError::DuplicateSlot { slot } => write!(f, "Slot {} given more than once", slot),
+            FrameError::EmptyBulk => write!(f, "Bulk request without slots"),
+            FrameError::UnsupportedVersion { version } => write!(f, "Unsupported protocol version {}", version),
+            FrameError::UnknownCommand { command } => write!(f, "Unknown command 0x{:02x}", command),
+        }
+    }
+}
+
+impl std::error::Error for FrameError {}
+
+impl From<FrameError> for Error {
+    fn from(err: FrameError) -> Error {
+        Error::new(ErrorKind::InvalidData, err)
+    }
+}
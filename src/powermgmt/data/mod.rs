@@ -1,6 +1,11 @@
+pub use frameerror::*;
 pub use pinconfig::*;
 pub use powerconfig::*;
+pub use request::*;
+pub use units::*;
 
+mod frameerror;
 mod pinconfig;
 mod powerconfig;
-
+mod request;
+mod units;
@@ -1,4 +1,11 @@
-#[derive(Debug)]
+use super::units::MilliAmps;
+
+/// Voltage code of a pin supplied from the 5V rail
+pub const VOLTAGE_5V: u8 = 0;
+/// Voltage code of a pin supplied from the 12V rail
+pub const VOLTAGE_12V: u8 = 1;
+
+#[derive(Debug, Clone)]
 pub struct PinConfig {
     voltage: u8,
     current: u16,
@@ -14,4 +21,7 @@ impl PinConfig {
     pub fn current(&self) -> u16 {
         self.current
     }
-}
\ No newline at end of file
+    pub fn current_ma(&self) -> MilliAmps {
+        MilliAmps(self.current)
+    }
+}
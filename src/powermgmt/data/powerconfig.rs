@@ -1,70 +1,166 @@
-#![allow(dead_code)]
+use super::frameerror::FrameError;
+use super::pinconfig::{PinConfig, VOLTAGE_12V, VOLTAGE_5V};
+use super::request::{CMD_SET, CMD_TEST, HEADER_CLASS, HEADER_GROUP, HEADER_LEN};
+use super::units::{BudgetOverflow, MilliWatts, PowerBudget, Rail};
 
-use std::io::{Error, ErrorKind};
+/// Number of pins of the IO module
+pub const MAX_PINS: usize = 8;
 
-use super::pinconfig::PinConfig;
+pub(crate) const PIN_LEN: usize = 3;
 
+/// Power used on the 5V rail by each pin supplied with 5V (on top of the load)
+const PIN_OVERHEAD_5V: MilliWatts = MilliWatts(200);
+/// Power used on the 5V rail by each pin supplied with 12V
+const PIN_OVERHEAD_12V: MilliWatts = MilliWatts(400);
+
+/// Power configuration of all pins of one slot.
+///
+/// Wire format (multi-byte values are big-endian):
+///
+/// | Offset    | Size | Content                                     |
+/// |-----------|------|---------------------------------------------|
+/// | 0         | 1    | Slot                                        |
+/// | 1         | 3    | `0x03 0x03` and command, see [`PowerRequest`](super::PowerRequest) |
+/// | 4 + 3 * n | 1    | Voltage code of pin n (0 = 5V, 1 = 12V)     |
+/// | 5 + 3 * n | 2    | Current of pin n in mA                      |
+///
+/// At most [`MAX_PINS`] pin records may follow the header.
+#[derive(Debug, Clone)]
 pub struct PowerConfig {
     device_id: u8,
     pins: Vec<PinConfig>,
 
-    idle_3v3: u16,
-    idle_5v0: u16,
-    idle_12v: u16,
+    idle_3v3: MilliWatts,
+    idle_5v0: MilliWatts,
+    idle_12v: MilliWatts,
 
 }
 
 impl PowerConfig {
-    pub(crate) fn new(frame: Vec<u8>) -> Result<PowerConfig, Error> {
-        let mut pins: Vec<PinConfig> = vec![];
-        let device_id = match frame[0..4] {
-            [id, 0x03, 0x03, 0x02] | [id, 0x03, 0x03, 0x03] => id,
-            _ => return Err(Error::new(ErrorKind::InvalidData, format!("Wrong frame header: {:?}", &frame[0..4]))),
+    pub(crate) fn new(frame: Vec<u8>) -> Result<PowerConfig, FrameError> {
+        if frame.len() < HEADER_LEN {
+            return Err(FrameError::TooShort { len: frame.len() });
+        }
+
+        let device_id = match frame[0..HEADER_LEN] {
+            [id, HEADER_CLASS, HEADER_GROUP, CMD_TEST] | [id, HEADER_CLASS, HEADER_GROUP, CMD_SET] => id,
+            _ => return Err(FrameError::BadHeader { header: [frame[0], frame[1], frame[2], frame[3]] }),
         };
 
-        let payload = &frame.as_slice()[4..];
-        if payload.len() % 2 != 0 {
-            return Err(Error::new(ErrorKind::InvalidData, "Wrong length"));
+        return PowerConfig::from_payload(device_id, &frame[HEADER_LEN..]);
+    }
+
+    /// Parses the pin records following the frame header.
+    pub(crate) fn from_payload(device_id: u8, payload: &[u8]) -> Result<PowerConfig, FrameError> {
+        let count = payload.len() / PIN_LEN;
+        if payload.len() % PIN_LEN != 0 {
+            return Err(FrameError::TruncatedPin { index: count, remaining: payload.len() % PIN_LEN });
+        }
+        if count > MAX_PINS {
+            return Err(FrameError::TooManyPins { count, max: MAX_PINS });
         }
 
-        for i in (0..payload.len()).step_by(3) {
-            let mut tmp: u16 = 0;
-            tmp |= (payload[i + 1] as u16) << 8;
-            tmp |= payload[i + 2] as u16;
+        let mut pins: Vec<PinConfig> = Vec::with_capacity(count);
+        for (index, record) in payload.chunks_exact(PIN_LEN).enumerate() {
+            let voltage = record[0];
+            if voltage != VOLTAGE_5V && voltage != VOLTAGE_12V {
+                return Err(FrameError::UnknownVoltage { index, code: voltage });
+            }
+            pins.push(PinConfig::new(voltage, u16::from_be_bytes([record[1], record[2]])));
+        }
 
-            pins.push(PinConfig::new(payload[i], tmp))
+        return Ok(PowerConfig { device_id, pins, idle_3v3: MilliWatts(0), idle_5v0: MilliWatts(0), idle_12v: MilliWatts(0) });
+    }
+
+    /// Builds a config from pins, applying the same checks as the frame parser.
+    pub fn from_pins(device_id: u8, pins: Vec<PinConfig>) -> Result<PowerConfig, FrameError> {
+        if pins.len() > MAX_PINS {
+            return Err(FrameError::TooManyPins { count: pins.len(), max: MAX_PINS });
+        }
+        for (index, pin) in pins.iter().enumerate() {
+            if pin.voltage() != VOLTAGE_5V && pin.voltage() != VOLTAGE_12V {
+                return Err(FrameError::UnknownVoltage { index, code: pin.voltage() });
+            }
         }
+        return Ok(PowerConfig { device_id, pins, idle_3v3: MilliWatts(0), idle_5v0: MilliWatts(0), idle_12v: MilliWatts(0) });
+    }
 
-        return Ok(PowerConfig { device_id, pins, idle_3v3: 0, idle_5v0: 0, idle_12v: 0 });
+    /// Encodes the config as a set request frame which [`PowerConfig::new`] accepts.
+    pub fn to_frame(&self) -> Vec<u8> {
+        let mut frame = Vec::with_capacity(HEADER_LEN + self.pins.len() * PIN_LEN);
+        frame.extend([self.device_id, HEADER_CLASS, HEADER_GROUP, CMD_SET]);
+        frame.extend(self.to_payload());
+        frame
+    }
+
+    /// Encodes the pin records which [`PowerConfig::from_payload`] accepts.
+    pub fn to_payload(&self) -> Vec<u8> {
+        let mut payload = Vec::with_capacity(self.pins.len() * PIN_LEN);
+        for pin in &self.pins {
+            payload.push(pin.voltage());
+            payload.extend(pin.current().to_be_bytes());
+        }
+        payload
     }
 
-    pub fn get_power_3v3(&self) -> u16 {
+    pub fn get_power_3v3(&self) -> MilliWatts {
         return self.idle_3v3;
     }
 
-    pub fn get_power_5v5(&self) -> u16 {
-        let mut power: u16 = 0;
+    pub fn get_power_5v5(&self) -> Result<MilliWatts, BudgetOverflow> {
+        let mut power = self.idle_5v0;
         for pin in &self.pins {
-            if pin.voltage() == 0 {
-                power += pin.current() * 5 + 200;
+            let pin_power = if pin.voltage() == VOLTAGE_5V {
+                pin.current_ma().at_volts(5).checked_add(PIN_OVERHEAD_5V)
             } else {
-                power += 400; // 400mW for each 12V config is used on 5V rail!
-            }
+                Some(PIN_OVERHEAD_12V) // 12V configs also draw from the 5V rail!
+            };
+            power = match pin_power.and_then(|value| power.checked_add(value)) {
+                Some(value) => value,
+                None => return Err(PowerConfig::overflow(Rail::V5V0)),
+            };
         }
-        return self.idle_5v0 + power;
+        return Ok(power);
     }
 
-    pub fn get_power_12v(&self) -> u16 {
-        let mut power: u16 = 0;
+    pub fn get_power_12v(&self) -> Result<MilliWatts, BudgetOverflow> {
+        let mut power = self.idle_12v;
         for pin in &self.pins {
-            if pin.voltage() == 1 {
-                power += pin.current() * 12;
+            if pin.voltage() == VOLTAGE_12V {
+                power = match power.checked_add(pin.current_ma().at_volts(12)) {
+                    Some(value) => value,
+                    None => return Err(PowerConfig::overflow(Rail::V12)),
+                };
             }
         }
+        return Ok(power);
+    }
+
+    /// Total power of the config per rail including the idle power.
+    pub fn budget(&self) -> Result<PowerBudget, BudgetOverflow> {
+        let power_5v0 = match self.get_power_5v5() {
+            Ok(value) => value,
+            Err(err) => return Err(err),
+        };
+        let power_12v = match self.get_power_12v() {
+            Ok(value) => value,
+            Err(err) => return Err(err),
+        };
+        return Ok(PowerBudget { power_3v3: self.get_power_3v3(), power_5v0, power_12v });
+    }
 
-        return self.idle_12v + power;
+    /// Power drawn by the pins alone, without the idle power of the module.
+    pub fn pin_load(&self) -> Result<PowerBudget, BudgetOverflow> {
+        let mut load = self.clone();
+        load.idle_3v3 = MilliWatts(0);
+        load.idle_5v0 = MilliWatts(0);
+        load.idle_12v = MilliWatts(0);
+        return load.budget();
     }
 
+    fn overflow(rail: Rail) -> BudgetOverflow {
+        BudgetOverflow { rail, power: None }
+    }
 
     pub(crate) fn get_device_id(&self) -> u8 {
         return self.device_id;
@@ -81,15 +177,155 @@ impl PowerConfig {
         result
     }
 
-    pub fn set_idle_power_3v3(&mut self, power: u16) {
+    pub fn set_idle_power_3v3(&mut self, power: MilliWatts) {
         self.idle_3v3 = power;
     }
 
-    pub fn set_idle_power_5v0(&mut self, power: u16) {
+    pub fn set_idle_power_5v0(&mut self, power: MilliWatts) {
         self.idle_5v0 = power;
     }
 
-    pub fn set_idle_power_12v(&mut self, power: u16) {
+    pub fn set_idle_power_12v(&mut self, power: MilliWatts) {
         self.idle_12v = power;
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    fn pins() -> impl Strategy<Value = Vec<PinConfig>> {
+        let pin = (prop_oneof![Just(VOLTAGE_5V), Just(VOLTAGE_12V)], any::<u16>()).prop_map(|(voltage, current)| PinConfig::new(voltage, current));
+        prop::collection::vec(pin, 0..=MAX_PINS)
+    }
+
+    #[test]
+    fn rejects_short_frames() {
+        for len in 0..HEADER_LEN {
+            assert_eq!(PowerConfig::new(vec![1; len]).unwrap_err(), FrameError::TooShort { len });
+        }
+    }
+
+    #[test]
+    fn rejects_bad_header() {
+        assert_eq!(PowerConfig::new(vec![1, HEADER_CLASS, 0x04, CMD_SET]).unwrap_err(), FrameError::BadHeader { header: [1, HEADER_CLASS, 0x04, CMD_SET] });
+    }
+
+    #[test]
+    fn rejects_truncated_pin() {
+        assert_eq!(PowerConfig::from_payload(1, &[VOLTAGE_5V, 0, 100, VOLTAGE_12V]).unwrap_err(), FrameError::TruncatedPin { index: 1, remaining: 1 });
+        assert_eq!(PowerConfig::from_payload(1, &[VOLTAGE_5V, 0]).unwrap_err(), FrameError::TruncatedPin { index: 0, remaining: 2 });
+    }
+
+    #[test]
+    fn rejects_too_many_pins() {
+        let payload = [VOLTAGE_5V, 0, 100].repeat(MAX_PINS + 1);
+        assert_eq!(PowerConfig::from_payload(1, &payload).unwrap_err(), FrameError::TooManyPins { count: MAX_PINS + 1, max: MAX_PINS });
+        assert_eq!(PowerConfig::from_pins(1, vec![PinConfig::new(VOLTAGE_5V, 100); MAX_PINS + 1]).unwrap_err(), FrameError::TooManyPins { count: MAX_PINS + 1, max: MAX_PINS });
+    }
+
+    #[test]
+    fn rejects_unknown_voltage() {
+        assert_eq!(PowerConfig::from_payload(1, &[VOLTAGE_5V, 0, 100, 7, 0, 100]).unwrap_err(), FrameError::UnknownVoltage { index: 1, code: 7 });
+        assert_eq!(PowerConfig::from_pins(1, vec![PinConfig::new(7, 100)]).unwrap_err(), FrameError::UnknownVoltage { index: 0, code: 7 });
+    }
+
+    #[test]
+    fn accepts_test_and_set_command() {
+        for command in [CMD_TEST, CMD_SET] {
+            let config = PowerConfig::new(vec![2, HEADER_CLASS, HEADER_GROUP, command, VOLTAGE_12V, 0x01, 0xF4]).expect("Frame rejected");
+            assert_eq!(config.get_device_id(), 2);
+            assert_eq!(config.pin_vec(), vec![(VOLTAGE_12V, 500)]);
+        }
+    }
+
+    #[test]
+    fn never_panics_on_short_input() {
+        let _ = PowerConfig::new(Vec::new());
+        let _ = PowerConfig::from_payload(1, &[]);
+        for a in 0..=u8::MAX {
+            let _ = PowerConfig::new(vec![a]);
+            let _ = PowerConfig::from_payload(1, &[a]);
+            for b in 0..=u8::MAX {
+                let _ = PowerConfig::new(vec![a, b]);
+                let _ = PowerConfig::from_payload(1, &[a, b]);
+            }
+        }
+    }
+
+    #[test]
+    fn power_per_rail() {
+        let mut config = PowerConfig::from_pins(1, vec![PinConfig::new(VOLTAGE_5V, 100), PinConfig::new(VOLTAGE_12V, 200)]).expect("Pins rejected");
+        config.set_idle_power_3v3(MilliWatts(10));
+        config.set_idle_power_5v0(MilliWatts(20));
+        config.set_idle_power_12v(MilliWatts(30));
+        let budget = config.budget().expect("Budget overflow");
+        assert_eq!(budget.power_3v3, MilliWatts(10));
+        assert_eq!(budget.power_5v0, MilliWatts(20 + 100 * 5 + 200 + 400));
+        assert_eq!(budget.power_12v, MilliWatts(30 + 200 * 12));
+        assert_eq!(config.pin_load().expect("Budget overflow"), PowerBudget { power_3v3: MilliWatts(0), power_5v0: MilliWatts(100 * 5 + 200 + 400), power_12v: MilliWatts(200 * 12) });
+    }
+
+    #[test]
+    fn max_current_on_all_pins_5v() {
+        let config = PowerConfig::from_pins(1, vec![PinConfig::new(VOLTAGE_5V, u16::MAX); MAX_PINS]).expect("Pins rejected");
+        assert_eq!(config.get_power_5v5(), Ok(MilliWatts(8 * (65_535 * 5 + 200))));
+        assert_eq!(config.get_power_12v(), Ok(MilliWatts(0)));
+        let err = config.budget().expect("Budget overflow").to_wire().unwrap_err();
+        assert_eq!(err, BudgetOverflow { rail: Rail::V5V0, power: Some(MilliWatts(8 * (65_535 * 5 + 200))) });
+    }
+
+    #[test]
+    fn max_current_on_all_pins_12v() {
+        let config = PowerConfig::from_pins(1, vec![PinConfig::new(VOLTAGE_12V, u16::MAX); MAX_PINS]).expect("Pins rejected");
+        assert_eq!(config.get_power_5v5(), Ok(MilliWatts(8 * 400)));
+        assert_eq!(config.get_power_12v(), Ok(MilliWatts(8 * 65_535 * 12)));
+        let err = config.budget().expect("Budget overflow").to_wire().unwrap_err();
+        assert_eq!(err, BudgetOverflow { rail: Rail::V12, power: Some(MilliWatts(8 * 65_535 * 12)) });
+    }
+
+    #[test]
+    fn idle_power_overflow_is_reported() {
+        let mut config = PowerConfig::from_pins(1, vec![PinConfig::new(VOLTAGE_5V, 1)]).expect("Pins rejected");
+        config.set_idle_power_5v0(MilliWatts(u32::MAX));
+        assert_eq!(config.get_power_5v5(), Err(BudgetOverflow { rail: Rail::V5V0, power: None }));
+    }
+
+    proptest! {
+        #[test]
+        fn new_never_panics(frame in prop::collection::vec(any::<u8>(), 0..64)) {
+            let _ = PowerConfig::new(frame);
+        }
+
+        #[test]
+        fn new_never_panics_after_header(slot: u8, command: u8, payload in prop::collection::vec(any::<u8>(), 0..64)) {
+            let mut frame = vec![slot, HEADER_CLASS, HEADER_GROUP, command];
+            frame.extend(payload);
+            let _ = PowerConfig::new(frame);
+        }
+
+        #[test]
+        fn from_payload_never_panics(slot: u8, payload in prop::collection::vec(any::<u8>(), 0..64)) {
+            let _ = PowerConfig::from_payload(slot, &payload);
+        }
+
+        #[test]
+        fn frame_round_trip(slot: u8, pins in pins()) {
+            let config = PowerConfig::from_pins(slot, pins).expect("Pins rejected");
+            let parsed = PowerConfig::new(config.to_frame()).expect("Frame rejected");
+            prop_assert_eq!(parsed.get_device_id(), slot);
+            prop_assert_eq!(parsed.pin_vec(), config.pin_vec());
+            prop_assert_eq!(parsed.to_frame(), config.to_frame());
+        }
+
+        #[test]
+        fn payload_round_trip(slot: u8, pins in pins()) {
+            let config = PowerConfig::from_pins(slot, pins).expect("Pins rejected");
+            let parsed = PowerConfig::from_payload(slot, &config.to_payload()).expect("Payload rejected");
+            prop_assert_eq!(parsed.pin_vec(), config.pin_vec());
+            prop_assert_eq!(parsed.to_payload(), config.to_payload());
+        }
+    }
+}
@@ -0,0 +1,389 @@
+use super::frameerror::FrameError;
+use super::pinconfig::{VOLTAGE_12V, VOLTAGE_5V};
+use super::powerconfig::{PowerConfig, MAX_PINS, PIN_LEN};
+
+pub(crate) const HEADER_LEN: usize = 4;
+pub(crate) const HEADER_CLASS: u8 = 0x03;
+pub(crate) const HEADER_GROUP: u8 = 0x03;
+
+/// Test and apply a power config (legacy, handled like [`CMD_SET`])
+pub const CMD_TEST: u8 = 0x02;
+/// Test and apply a power config
+pub const CMD_SET: u8 = 0x03;
+/// Test a power config against the module and power-mgmt without applying it
+pub const CMD_VALIDATE: u8 = 0x04;
+/// Read the power config the driver last applied to a slot
+pub const CMD_QUERY: u8 = 0x05;
+/// Test and apply the power configs of several slots, either all or none are applied
+pub const CMD_BULK: u8 = 0x06;
+/// Describe the supported protocol versions, commands, pins and units
+pub const CMD_CAPABILITIES: u8 = 0x07;
+/// Read the budget every slot has reserved from power-mgmt
+pub const CMD_LEDGER: u8 = 0x08;
+/// Read the health of the connection to power-mgmt
+pub const CMD_HEALTH: u8 = 0x09;
+/// Versioned request, see [`RequestFrame`]
+pub const CMD_VERSIONED: u8 = 0x10;
+
+/// Latest version of the versioned protocol
+pub const PROTOCOL_VERSION: u8 = 1;
+
+/// Commands supported in versioned requests
+const VERSIONED_COMMANDS: [u8; 7] = [CMD_SET, CMD_VALIDATE, CMD_QUERY, CMD_BULK, CMD_CAPABILITIES, CMD_LEDGER, CMD_HEALTH];
+/// Unit code of currents: mA
+const UNIT_MILLIAMPS: u8 = 0;
+/// Unit code of power values: mW
+const UNIT_MILLIWATTS: u8 = 0;
+
+/// Request received on the PowerMgmt virtual device.
+///
+/// Every frame starts with the header `[slot, 0x03, 0x03, command]`,
+/// the payload depends on the command.
+pub enum PowerRequest {
+    /// [`CMD_CAPABILITIES`]: no payload, the response is described in [`capabilities`]
+    Capabilities,
+    /// [`CMD_TEST`], [`CMD_SET`]: pin records as described in [`PowerConfig`]
+    Apply(PowerConfig),
+    /// [`CMD_VALIDATE`]: pin records as described in [`PowerConfig`]
+    Validate(PowerConfig),
+    /// [`CMD_QUERY`]: no payload
+    ///
+    /// The response contains the 3V3, 5V and 12V budget in mW as big-endian u16
+    /// followed by the pin records as described in [`PowerConfig`].
+    /// The budget of a slot is u16 like in the request to power-mgmt, unlike the totals of [`CMD_LEDGER`].
+    Query(u8),
+    /// [`CMD_BULK`]: for every slot the slot number, the number of pin records
+    /// and the pin records as described in [`PowerConfig`], the slot in the header is ignored
+    ///
+    /// The response contains six zero bytes like a successful [`CMD_SET`], a budget rejected
+    /// by power-mgmt is reported as budget exceeded error (code 4) with the power exceeding it per rail.
+    Bulk(Vec<PowerConfig>),
+    /// [`CMD_LEDGER`]: no payload, the slot in the header is ignored
+    ///
+    /// The response contains the number of slots, for every slot the slot number and its 3V3, 5V
+    /// and 12V budget (idle power plus pin load) in mW as big-endian u32, followed by the totals per rail.
+    /// The values are wider than the u16 of [`CMD_QUERY`] as the totals of all slots may exceed it.
+    Ledger,
+    /// [`CMD_HEALTH`]: no payload, the slot in the header is ignored
+    ///
+    /// The response contains the state of the connection (0 = not connected yet, 1 = connected,
+    /// 2 = unreachable) followed by the failed attempts since it was lost as big-endian u32.
+    Health,
+}
+
+impl PowerRequest {
+    pub(crate) fn parse(frame: Vec<u8>) -> Result<PowerRequest, FrameError> {
+        if frame.len() < HEADER_LEN {
+            return Err(FrameError::TooShort { len: frame.len() });
+        }
+
+        match frame[0..HEADER_LEN] {
+            [_, HEADER_CLASS, HEADER_GROUP, CMD_TEST] | [_, HEADER_CLASS, HEADER_GROUP, CMD_SET] => {
+                match PowerConfig::new(frame) {
+                    Ok(config) => Ok(PowerRequest::Apply(config)),
+                    Err(err) => Err(err),
+                }
+            }
+            [slot, HEADER_CLASS, HEADER_GROUP, CMD_VALIDATE] => {
+                match PowerConfig::from_payload(slot, &frame[HEADER_LEN..]) {
+                    Ok(config) => Ok(PowerRequest::Validate(config)),
+                    Err(err) => Err(err),
+                }
+            }
+            [slot, HEADER_CLASS, HEADER_GROUP, CMD_QUERY] => {
+                match frame.len() {
+                    HEADER_LEN => Ok(PowerRequest::Query(slot)),
+                    len => Err(FrameError::UnexpectedPayload { len: len - HEADER_LEN }),
+                }
+            }
+            [_, HEADER_CLASS, HEADER_GROUP, CMD_CAPABILITIES] => {
+                match frame.len() {
+                    HEADER_LEN => Ok(PowerRequest::Capabilities),
+                    len => Err(FrameError::UnexpectedPayload { len: len - HEADER_LEN }),
+                }
+            }
+            [_, HEADER_CLASS, HEADER_GROUP, CMD_LEDGER] => {
+                match frame.len() {
+                    HEADER_LEN => Ok(PowerRequest::Ledger),
+                    len => Err(FrameError::UnexpectedPayload { len: len - HEADER_LEN }),
+                }
+            }
+            [_, HEADER_CLASS, HEADER_GROUP, CMD_HEALTH] => {
+                match frame.len() {
+                    HEADER_LEN => Ok(PowerRequest::Health),
+                    len => Err(FrameError::UnexpectedPayload { len: len - HEADER_LEN }),
+                }
+            }
+            [_, HEADER_CLASS, HEADER_GROUP, CMD_BULK] => {
+                match PowerRequest::parse_bulk(&frame[HEADER_LEN..]) {
+                    Ok(configs) => Ok(PowerRequest::Bulk(configs)),
+                    Err(err) => Err(err),
+                }
+            }
+            _ => Err(FrameError::BadHeader { header: [frame[0], frame[1], frame[2], frame[3]] }),
+        }
+    }
+
+    /// Command of the request as used in versioned responses.
+    pub fn command(&self) -> u8 {
+        match self {
+            PowerRequest::Capabilities => CMD_CAPABILITIES,
+            PowerRequest::Apply(_) => CMD_SET,
+            PowerRequest::Validate(_) => CMD_VALIDATE,
+            PowerRequest::Query(_) => CMD_QUERY,
+            PowerRequest::Bulk(_) => CMD_BULK,
+            PowerRequest::Ledger => CMD_LEDGER,
+            PowerRequest::Health => CMD_HEALTH,
+        }
+    }
+
+    /// Slots whose modules the request sends commands to, in ascending order.
+    pub fn slots(&self) -> Vec<u8> {
+        let mut slots = match self {
+            PowerRequest::Apply(cmd) | PowerRequest::Validate(cmd) => vec![cmd.get_device_id()],
+            PowerRequest::Bulk(configs) => configs.iter().map(|config| config.get_device_id()).collect(),
+            PowerRequest::Query(_) | PowerRequest::Capabilities | PowerRequest::Ledger | PowerRequest::Health => Vec::new(),
+        };
+        slots.sort_unstable();
+        slots.dedup();
+        slots
+    }
+
+    fn parse_bulk(mut payload: &[u8]) -> Result<Vec<PowerConfig>, FrameError> {
+        let mut configs: Vec<PowerConfig> = Vec::new();
+        while !payload.is_empty() {
+            if payload.len() < 2 {
+                return Err(FrameError::TruncatedSlot { remaining: payload.len() });
+            }
+            let slot = payload[0];
+            let end = 2 + payload[1] as usize * PIN_LEN;
+            if payload.len() < end {
+                let pins = payload.len() - 2;
+                return Err(FrameError::TruncatedPin { index: pins / PIN_LEN, remaining: pins % PIN_LEN });
+            }
+            if configs.iter().any(|config| config.get_device_id() == slot) {
+                return Err(FrameError::DuplicateSlot { slot });
+            }
+
+            match PowerConfig::from_payload(slot, &payload[2..end]) {
+                Ok(config) => configs.push(config),
+                Err(err) => return Err(err),
+            }
+            payload = &payload[end..];
+        }
+
+        match configs.is_empty() {
+            true => Err(FrameError::EmptyBulk),
+            false => Ok(configs),
+        }
+    }
+}
+
+/// Request frame of either protocol.
+///
+/// Legacy frames are `[slot, 0x03, 0x03, command, payload...]`.
+/// Versioned frames are `[slot, 0x03, 0x03, 0x10, version, command, payload...]`
+/// with the payload of the command, their response is prefixed with `[version, command]`.
+pub struct RequestFrame {
+    /// Protocol version, `None` for legacy frames
+    pub version: Option<u8>,
+    pub request: PowerRequest,
+}
+
+impl RequestFrame {
+    pub(crate) fn parse(frame: Vec<u8>) -> Result<RequestFrame, FrameError> {
+        match frame.get(0..HEADER_LEN) {
+            Some([slot, HEADER_CLASS, HEADER_GROUP, CMD_VERSIONED]) => {
+                if frame.len() < HEADER_LEN + 2 {
+                    return Err(FrameError::TooShort { len: frame.len() });
+                }
+                let (version, command) = (frame[HEADER_LEN], frame[HEADER_LEN + 1]);
+                if version == 0 || version > PROTOCOL_VERSION {
+                    return Err(FrameError::UnsupportedVersion { version });
+                }
+                if !VERSIONED_COMMANDS.contains(&command) {
+                    return Err(FrameError::UnknownCommand { command });
+                }
+
+                let mut inner = vec![*slot, HEADER_CLASS, HEADER_GROUP, command];
+                inner.extend_from_slice(&frame[HEADER_LEN + 2..]);
+                match PowerRequest::parse(inner) {
+                    Ok(request) => Ok(RequestFrame { version: Some(version), request }),
+                    Err(err) => Err(err),
+                }
+            }
+            _ => match PowerRequest::parse(frame) {
+                Ok(request) => Ok(RequestFrame { version: None, request }),
+                Err(err) => Err(err),
+            },
+        }
+    }
+
+    /// Version and command of a versioned frame, also if its payload is malformed.
+    pub(crate) fn versioned_header(frame: &[u8]) -> Option<(u8, u8)> {
+        match frame {
+            [_, HEADER_CLASS, HEADER_GROUP, CMD_VERSIONED, version, command, ..] => Some((*version, *command)),
+            _ => None,
+        }
+    }
+
+    /// Adds the versioned prefix to the response payload of a request.
+    pub fn encode_response(version: Option<u8>, command: u8, payload: Vec<u8>) -> Vec<u8> {
+        match version {
+            Some(version) => {
+                let mut response = vec![version, command];
+                response.extend(payload);
+                response
+            }
+            None => payload,
+        }
+    }
+}
+
+/// Response to [`CMD_CAPABILITIES`]:
+///
+/// | Size      | Content                                             |
+/// |-----------|-----------------------------------------------------|
+/// | 1         | Latest protocol version                             |
+/// | 1         | Number of commands n                                |
+/// | n         | Commands supported in versioned requests            |
+/// | 1         | Max pins per slot                                   |
+/// | 1         | Number of voltage codes m                           |
+/// | 2 * m     | Voltage code and voltage in V                       |
+/// | 1         | Unit of currents (0 = mA)                           |
+/// | 1         | Unit of power values (0 = mW)                       |
+pub fn capabilities() -> Vec<u8> {
+    let mut response = vec![PROTOCOL_VERSION, VERSIONED_COMMANDS.len() as u8];
+    response.extend(VERSIONED_COMMANDS);
+    response.push(MAX_PINS as u8);
+    response.extend([2, VOLTAGE_5V, 5, VOLTAGE_12V, 12]);
+    response.push(UNIT_MILLIAMPS);
+    response.push(UNIT_MILLIWATTS);
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use proptest::prelude::*;
+
+    use super::*;
+    use crate::powermgmt::data::PinConfig;
+
+    fn versioned(slot: u8, version: u8, command: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![slot, HEADER_CLASS, HEADER_GROUP, CMD_VERSIONED, version, command];
+        frame.extend_from_slice(payload);
+        frame
+    }
+
+    #[test]
+    fn parses_legacy_set() {
+        let config = PowerConfig::from_pins(3, vec![PinConfig::new(VOLTAGE_5V, 100), PinConfig::new(VOLTAGE_12V, 200)]).expect("Pins rejected");
+        let frame = RequestFrame::parse(config.to_frame()).expect("Frame rejected");
+        assert_eq!(frame.version, None);
+        match frame.request {
+            PowerRequest::Apply(parsed) => assert_eq!(parsed.pin_vec(), config.pin_vec()),
+            _ => panic!("Expected a set request"),
+        }
+    }
+
+    #[test]
+    fn parses_versioned_set() {
+        let config = PowerConfig::from_pins(3, vec![PinConfig::new(VOLTAGE_12V, 500)]).expect("Pins rejected");
+        let frame = RequestFrame::parse(versioned(3, PROTOCOL_VERSION, CMD_SET, &config.to_payload())).expect("Frame rejected");
+        assert_eq!(frame.version, Some(PROTOCOL_VERSION));
+        match frame.request {
+            PowerRequest::Apply(parsed) => {
+                assert_eq!(parsed.get_device_id(), 3);
+                assert_eq!(parsed.pin_vec(), config.pin_vec());
+            }
+            _ => panic!("Expected a set request"),
+        }
+    }
+
+    #[test]
+    fn parses_bulk() {
+        let payload = [1, 1, VOLTAGE_5V, 0, 100, 2, 0];
+        match RequestFrame::parse(versioned(0, PROTOCOL_VERSION, CMD_BULK, &payload)).expect("Frame rejected").request {
+            PowerRequest::Bulk(configs) => {
+                assert_eq!(configs.len(), 2);
+                assert_eq!(configs[0].pin_vec(), vec![(VOLTAGE_5V, 100)]);
+                assert!(configs[1].pin_vec().is_empty());
+            }
+            _ => panic!("Expected a bulk request"),
+        }
+    }
+
+    #[test]
+    fn rejects_malformed_bulk() {
+        let parse = |payload: &[u8]| RequestFrame::parse(versioned(0, PROTOCOL_VERSION, CMD_BULK, payload)).err();
+        assert_eq!(parse(&[]), Some(FrameError::EmptyBulk));
+        assert_eq!(parse(&[1]), Some(FrameError::TruncatedSlot { remaining: 1 }));
+        assert_eq!(parse(&[1, 1, VOLTAGE_5V]), Some(FrameError::TruncatedPin { index: 0, remaining: 1 }));
+        assert_eq!(parse(&[1, 0, 1, 0]), Some(FrameError::DuplicateSlot { slot: 1 }));
+    }
+
+    #[test]
+    fn rejects_unknown_version_and_command() {
+        assert_eq!(RequestFrame::parse(versioned(1, 0, CMD_SET, &[])).err(), Some(FrameError::UnsupportedVersion { version: 0 }));
+        assert_eq!(RequestFrame::parse(versioned(1, PROTOCOL_VERSION + 1, CMD_SET, &[])).err(), Some(FrameError::UnsupportedVersion { version: PROTOCOL_VERSION + 1 }));
+        assert_eq!(RequestFrame::parse(versioned(1, PROTOCOL_VERSION, CMD_TEST, &[])).err(), Some(FrameError::UnknownCommand { command: CMD_TEST }));
+        assert_eq!(RequestFrame::parse(vec![1, HEADER_CLASS, HEADER_GROUP, CMD_VERSIONED, 1]).err(), Some(FrameError::TooShort { len: 5 }));
+    }
+
+    #[test]
+    fn rejects_payload_of_commands_without_payload() {
+        for command in [CMD_QUERY, CMD_CAPABILITIES, CMD_LEDGER, CMD_HEALTH] {
+            assert_eq!(RequestFrame::parse(vec![1, HEADER_CLASS, HEADER_GROUP, command, 0]).err(), Some(FrameError::UnexpectedPayload { len: 1 }));
+        }
+    }
+
+    #[test]
+    fn prefixes_versioned_responses() {
+        assert_eq!(RequestFrame::encode_response(None, CMD_SET, vec![0; 6]), vec![0; 6]);
+        assert_eq!(RequestFrame::encode_response(Some(1), CMD_QUERY, vec![9]), vec![1, CMD_QUERY, 9]);
+    }
+
+    #[test]
+    fn never_panics_on_short_input() {
+        let _ = RequestFrame::parse(Vec::new());
+        for a in 0..=u8::MAX {
+            let _ = RequestFrame::parse(vec![a]);
+            for b in 0..=u8::MAX {
+                let _ = RequestFrame::parse(vec![a, b]);
+            }
+        }
+        for command in 0..=u8::MAX {
+            let _ = RequestFrame::parse(vec![1, HEADER_CLASS, HEADER_GROUP, command]);
+            for byte in 0..=u8::MAX {
+                let _ = RequestFrame::parse(vec![1, HEADER_CLASS, HEADER_GROUP, command, byte]);
+                let _ = RequestFrame::parse(versioned(1, command, byte, &[]));
+                let _ = RequestFrame::parse(versioned(1, command, byte, &[1]));
+            }
+        }
+    }
+
+    #[test]
+    fn capabilities_layout() {
+        // Version 1, 7 commands, 8 pins, 2 voltage codes, mA and mW
+        assert_eq!(capabilities(), vec![1, 7, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 8, 2, 0, 5, 1, 12, 0, 0]);
+    }
+
+    proptest! {
+        #[test]
+        fn parse_never_panics(frame in prop::collection::vec(any::<u8>(), 0..64)) {
+            let _ = RequestFrame::parse(frame);
+        }
+
+        #[test]
+        fn parse_never_panics_after_header(slot: u8, command: u8, payload in prop::collection::vec(any::<u8>(), 0..64)) {
+            let mut frame = vec![slot, HEADER_CLASS, HEADER_GROUP, command];
+            frame.extend(payload);
+            let _ = RequestFrame::parse(frame);
+        }
+
+        #[test]
+        fn parse_never_panics_on_versioned(slot: u8, version in 0..=PROTOCOL_VERSION + 1, command: u8, payload in prop::collection::vec(any::<u8>(), 0..64)) {
+            let _ = RequestFrame::parse(versioned(slot, version, command, &payload));
+        }
+    }
+}
@@ -0,0 +1,167 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+/// Supply rails of a slot
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Rail {
+    V3V3,
+    V5V0,
+    V12,
+}
+
+impl fmt::Display for Rail {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rail::V3V3 => write!(f, "3V3"),
+            Rail::V5V0 => write!(f, "5V"),
+            Rail::V12 => write!(f, "12V"),
+        }
+    }
+}
+
+/// Current in mA
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct MilliAmps(pub u16);
+
+impl MilliAmps {
+    /// Power drawn from a rail with the given voltage in V.
+    pub fn at_volts(self, volts: u16) -> MilliWatts {
+        MilliWatts(self.0 as u32 * volts as u32)
+    }
+}
+
+/// Power in mW
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Default)]
+pub struct MilliWatts(pub u32);
+
+impl MilliWatts {
+    pub fn checked_add(self, other: MilliWatts) -> Option<MilliWatts> {
+        self.0.checked_add(other.0).map(MilliWatts)
+    }
+
+    pub fn saturating_sub(self, other: MilliWatts) -> MilliWatts {
+        MilliWatts(self.0.saturating_sub(other.0))
+    }
+
+    /// Converts to the 16 bit value used by power-mgmt.
+    pub fn to_wire(self, rail: Rail) -> Result<u16, BudgetOverflow> {
+        match u16::try_from(self.0) {
+            Ok(value) => Ok(value),
+            Err(_) => Err(BudgetOverflow { rail, power: Some(self) }),
+        }
+    }
+}
+
+impl From<u16> for MilliWatts {
+    fn from(value: u16) -> MilliWatts {
+        MilliWatts(value as u32)
+    }
+}
+
+impl fmt::Display for MilliWatts {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} mW", self.0)
+    }
+}
+
+/// Requested power of a rail exceeds what power-mgmt can represent (`u16::MAX` mW).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BudgetOverflow {
+    pub rail: Rail,
+    /// `None` if the sum does not even fit into a [`MilliWatts`]
+    pub power: Option<MilliWatts>,
+}
+
+impl fmt::Display for BudgetOverflow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.power {
+            Some(power) => write!(f, "Requested power on {} rail exceeds {} mW: {}", self.rail, u16::MAX, power),
+            None => write!(f, "Requested power on {} rail is not representable", self.rail),
+        }
+    }
+}
+
+impl std::error::Error for BudgetOverflow {}
+
+impl From<BudgetOverflow> for Error {
+    fn from(err: BudgetOverflow) -> Error {
+        Error::new(ErrorKind::InvalidInput, err)
+    }
+}
+
+/// Power of a slot per rail
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct PowerBudget {
+    pub power_3v3: MilliWatts,
+    pub power_5v0: MilliWatts,
+    pub power_12v: MilliWatts,
+}
+
+impl PowerBudget {
+    /// Adds another budget per rail, saturating at `u32::MAX`.
+    pub fn saturating_add(&self, other: &PowerBudget) -> PowerBudget {
+        let add = |a: MilliWatts, b: MilliWatts| a.checked_add(b).unwrap_or(MilliWatts(u32::MAX));
+        PowerBudget {
+            power_3v3: add(self.power_3v3, other.power_3v3),
+            power_5v0: add(self.power_5v0, other.power_5v0),
+            power_12v: add(self.power_12v, other.power_12v),
+        }
+    }
+
+    /// Returns the budget as `(3v3, 5v0, 12v)` in the format expected by power-mgmt.
+    pub fn to_wire(&self) -> Result<(u16, u16, u16), BudgetOverflow> {
+        let power_3v3 = match self.power_3v3.to_wire(Rail::V3V3) {
+            Ok(value) => value,
+            Err(err) => return Err(err),
+        };
+        let power_5v0 = match self.power_5v0.to_wire(Rail::V5V0) {
+            Ok(value) => value,
+            Err(err) => return Err(err),
+        };
+        let power_12v = match self.power_12v.to_wire(Rail::V12) {
+            Ok(value) => value,
+            Err(err) => return Err(err),
+        };
+        Ok((power_3v3, power_5v0, power_12v))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn at_volts_does_not_overflow() {
+        assert_eq!(MilliAmps(0).at_volts(12), MilliWatts(0));
+        assert_eq!(MilliAmps(1500).at_volts(5), MilliWatts(7500));
+        assert_eq!(MilliAmps(u16::MAX).at_volts(5), MilliWatts(327_675));
+        assert_eq!(MilliAmps(u16::MAX).at_volts(12), MilliWatts(786_420));
+    }
+
+    #[test]
+    fn checked_add_detects_overflow() {
+        assert_eq!(MilliWatts(u32::MAX - 1).checked_add(MilliWatts(1)), Some(MilliWatts(u32::MAX)));
+        assert_eq!(MilliWatts(u32::MAX).checked_add(MilliWatts(1)), None);
+    }
+
+    #[test]
+    fn to_wire_boundary() {
+        assert_eq!(MilliWatts(65_535).to_wire(Rail::V5V0), Ok(65_535));
+        assert_eq!(MilliWatts(65_536).to_wire(Rail::V5V0), Err(BudgetOverflow { rail: Rail::V5V0, power: Some(MilliWatts(65_536)) }));
+    }
+
+    #[test]
+    fn budget_to_wire_names_the_rail() {
+        let budget = PowerBudget { power_3v3: MilliWatts(1), power_5v0: MilliWatts(2), power_12v: MilliWatts(3) };
+        assert_eq!(budget.to_wire(), Ok((1, 2, 3)));
+        let budget = PowerBudget { power_12v: MilliWatts(70_000), ..budget };
+        assert_eq!(budget.to_wire().unwrap_err().rail, Rail::V12);
+    }
+
+    #[test]
+    fn saturating_add_saturates() {
+        let budget = PowerBudget { power_3v3: MilliWatts(u32::MAX), power_5v0: MilliWatts(1), power_12v: MilliWatts(2) };
+        let total = budget.saturating_add(&budget);
+        assert_eq!(total, PowerBudget { power_3v3: MilliWatts(u32::MAX), power_5v0: MilliWatts(2), power_12v: MilliWatts(4) });
+    }
+}
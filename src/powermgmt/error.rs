@@ -0,0 +1,182 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+
+use crate::powermgmt::data::{BudgetOverflow, FrameError, Rail};
+use crate::powermgmt::module::FirmwareStatus;
+
+/// Failures reported to PowerMgmt clients.
+///
+/// Every error response carries `ErrorValue` and `ErrorMsg`. Legacy requests get no `Response`, like before.
+/// The `Response` of a versioned request is `[version, command]` followed by the code (big-endian u16)
+/// and its details: the raw firmware status widened to a big-endian u32 for [`PowerMgmtError::ConfigRejected`],
+/// the 3V3, 5V and 12V power exceeding the budget in mW (big-endian u32 each) for
+/// [`PowerMgmtError::BudgetExceeded`] and [`PowerMgmtError::BudgetNotRepresentable`].
+/// Power exceeding the budget of a set or validate request is not an error, it is part of the regular response.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PowerMgmtError {
+    /// No module is connected to the slot
+    SlotNotConnected { slot: u8 },
+    /// Request frame could not be parsed
+    MalformedFrame(FrameError),
+    /// Module rejected the pin config, see [`FirmwareStatus`] why the status is not decoded
+    ConfigRejected { slot: u8, status: FirmwareStatus },
+    /// power-mgmt rejected the budget of a slot, `shortfall` is the power exceeding it per rail (3V3, 5V, 12V)
+    BudgetExceeded { slot: u8, shortfall: (u16, u16, u16) },
+    /// power-mgmt could not be reached or failed during the negotiation
+    PowerMgmtUnreachable { reason: String },
+    /// Module did not reply in time
+    Timeout { slot: u8 },
+    /// No power config was applied to the slot
+    NotConfigured { slot: u8 },
+    /// Power requested on a rail exceeds what power-mgmt can represent, reported as exceeded budget
+    BudgetNotRepresentable(BudgetOverflow),
+    Internal { reason: String },
+}
+
+impl PowerMgmtError {
+    pub fn code(&self) -> u16 {
+        match self {
+            PowerMgmtError::SlotNotConnected { .. } => 1,
+            PowerMgmtError::MalformedFrame(_) => 2,
+            PowerMgmtError::ConfigRejected { .. } => 3,
+            PowerMgmtError::BudgetExceeded { .. } => 4,
+            PowerMgmtError::PowerMgmtUnreachable { .. } => 5,
+            PowerMgmtError::Timeout { .. } => 6,
+            PowerMgmtError::NotConfigured { .. } => 7,
+            PowerMgmtError::BudgetNotRepresentable(_) => 4,
+            PowerMgmtError::Internal { .. } => 0xFF,
+        }
+    }
+
+    /// Name used in the retry policies of the config file.
+    pub fn name(&self) -> &'static str {
+        match self {
+            PowerMgmtError::SlotNotConnected { .. } => "slot_not_connected",
+            PowerMgmtError::MalformedFrame(_) => "malformed_frame",
+            PowerMgmtError::ConfigRejected { .. } => "config_rejected",
+            PowerMgmtError::BudgetExceeded { .. } => "budget_exceeded",
+            PowerMgmtError::PowerMgmtUnreachable { .. } => "power_mgmt_unreachable",
+            PowerMgmtError::Timeout { .. } => "timeout",
+            PowerMgmtError::NotConfigured { .. } => "not_configured",
+            PowerMgmtError::BudgetNotRepresentable(_) => "budget_not_representable",
+            PowerMgmtError::Internal { .. } => "internal",
+        }
+    }
+
+    pub fn to_wire(&self) -> Vec<u8> {
+        let mut wire = self.code().to_be_bytes().to_vec();
+        match self {
+            PowerMgmtError::ConfigRejected { status, .. } => wire.extend(u32::from(*status).to_be_bytes()),
+            PowerMgmtError::BudgetExceeded { shortfall, .. } => {
+                for power in [shortfall.0, shortfall.1, shortfall.2] {
+                    wire.extend((power as u32).to_be_bytes());
+                }
+            }
+            PowerMgmtError::BudgetNotRepresentable(err) => {
+                // Power beyond the u16 power-mgmt takes, unknown if even the wide sum overflowed
+                let excess = match err.power {
+                    Some(power) => power.0.saturating_sub(u16::MAX as u32),
+                    None => u32::MAX,
+                };
+                for rail in [Rail::V3V3, Rail::V5V0, Rail::V12] {
+                    let power: u32 = if rail == err.rail { excess } else { 0 };
+                    wire.extend(power.to_be_bytes());
+                }
+            }
+            _ => (),
+        }
+        wire
+    }
+
+    /// Recovers the error from an [`Error`], errors of other sources are internal.
+    pub fn from_io(err: &Error) -> PowerMgmtError {
+        if let Some(inner) = err.get_ref() {
+            if let Some(value) = inner.downcast_ref::<PowerMgmtError>() {
+                return value.clone();
+            }
+            if let Some(value) = inner.downcast_ref::<FrameError>() {
+                return PowerMgmtError::MalformedFrame(value.clone());
+            }
+            if let Some(value) = inner.downcast_ref::<BudgetOverflow>() {
+                return PowerMgmtError::BudgetNotRepresentable(*value);
+            }
+        }
+        PowerMgmtError::Internal { reason: err.to_string() }
+    }
+
+    fn kind(&self) -> ErrorKind {
+        match self {
+            PowerMgmtError::SlotNotConnected { .. } => ErrorKind::NotConnected,
+            PowerMgmtError::MalformedFrame(_) => ErrorKind::InvalidData,
+            PowerMgmtError::ConfigRejected { .. } | PowerMgmtError::BudgetExceeded { .. } | PowerMgmtError::BudgetNotRepresentable(_) => ErrorKind::InvalidInput,
+            PowerMgmtError::PowerMgmtUnreachable { .. } => ErrorKind::ConnectionAborted,
+            PowerMgmtError::Timeout { .. } => ErrorKind::TimedOut,
+            PowerMgmtError::NotConfigured { .. } => ErrorKind::NotFound,
+            PowerMgmtError::Internal { .. } => ErrorKind::Other,
+        }
+    }
+}
+
+impl fmt::Display for PowerMgmtError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PowerMgmtError::SlotNotConnected { slot } => write!(f, "Slot {} not connected", slot),
+            PowerMgmtError::MalformedFrame(err) => write!(f, "{}", err),
+            PowerMgmtError::ConfigRejected { slot, status } => write!(f, "Slot {} rejected the power config with firmware status {}", slot, status),
+            PowerMgmtError::BudgetExceeded { slot, shortfall } => write!(f, "power-mgmt rejected the budget of slot {}, exceeded by 3v3: {} mW 5v0: {} mW 12v: {} mW", slot, shortfall.0, shortfall.1, shortfall.2),
+            PowerMgmtError::PowerMgmtUnreachable { reason } => write!(f, "power-mgmt failed: {}", reason),
+            PowerMgmtError::Timeout { slot } => write!(f, "Slot {} did not reply in time", slot),
+            PowerMgmtError::NotConfigured { slot } => write!(f, "No power config applied to slot {}", slot),
+            PowerMgmtError::BudgetNotRepresentable(err) => write!(f, "{}", err),
+            PowerMgmtError::Internal { reason } => write!(f, "{}", reason),
+        }
+    }
+}
+
+impl std::error::Error for PowerMgmtError {}
+
+impl From<PowerMgmtError> for Error {
+    fn from(err: PowerMgmtError) -> Error {
+        Error::new(err.kind(), err)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::powermgmt::data::MilliWatts;
+
+    #[test]
+    fn recovers_typed_errors() {
+        let err: Error = PowerMgmtError::Timeout { slot: 2 }.into();
+        assert_eq!(PowerMgmtError::from_io(&err), PowerMgmtError::Timeout { slot: 2 });
+        let err: Error = FrameError::EmptyBulk.into();
+        assert_eq!(PowerMgmtError::from_io(&err), PowerMgmtError::MalformedFrame(FrameError::EmptyBulk));
+        let err = Error::new(ErrorKind::NotFound, "missing");
+        assert_eq!(PowerMgmtError::from_io(&err).code(), 0xFF);
+    }
+
+    #[test]
+    fn config_rejected_carries_raw_status() {
+        let err = PowerMgmtError::ConfigRejected { slot: 1, status: 0x21 };
+        assert_eq!(err.to_wire(), vec![0, 3, 0, 0, 0, 0x21]);
+        assert_eq!(err.to_string(), "Slot 1 rejected the power config with firmware status 33");
+    }
+
+    #[test]
+    fn budget_overflow_is_reported_as_exceeded_budget() {
+        let overflow = BudgetOverflow { rail: Rail::V12, power: Some(MilliWatts(70_000)) };
+        let err = PowerMgmtError::from_io(&overflow.into());
+        assert_eq!(err, PowerMgmtError::BudgetNotRepresentable(overflow));
+        assert_eq!(err.to_wire(), vec![0, 4, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x11, 0x71]);
+
+        let err = PowerMgmtError::BudgetNotRepresentable(BudgetOverflow { rail: Rail::V3V3, power: None });
+        assert_eq!(err.to_wire(), vec![0, 4, 0xFF, 0xFF, 0xFF, 0xFF, 0, 0, 0, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn budget_exceeded_carries_shortfall_per_rail() {
+        let err = PowerMgmtError::BudgetExceeded { slot: 2, shortfall: (1, 0x0203, 0) };
+        assert_eq!(err.to_wire(), vec![0, 4, 0, 0, 0, 1, 0, 0, 2, 3, 0, 0, 0, 0]);
+    }
+}
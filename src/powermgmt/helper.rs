@@ -5,6 +5,8 @@ use std::time::{Duration, Instant};
 use noreya_sdbp::datatypes::Descriptor;
 use noreya_sdbp::drv::core::SharedStats;
 
+use crate::powermgmt::error::PowerMgmtError;
+
 pub struct PowerMgmtHelper {
     slot: u16,
     dev: Descriptor,
@@ -12,6 +14,7 @@ pub struct PowerMgmtHelper {
 
 
 impl PowerMgmtHelper {
+    /// Looks up the descriptor of the module in a slot, the caller checked before that the slot is connected.
     pub fn new(slot: u16, shared: &mut SharedStats) -> Result<PowerMgmtHelper, Error> {
         let mut stats = shared.read();
         let mut desc = None;
@@ -22,7 +25,7 @@ impl PowerMgmtHelper {
         }
 
         if desc.is_none() {
-            return Err(Error::new(ErrorKind::NotConnected, format!("Slot {} not connected", slot)));
+            return Err(PowerMgmtError::Internal { reason: format!("Module in slot {} is not enumerated yet", slot) }.into());
         }
 
         return Ok(PowerMgmtHelper {
@@ -1,284 +1,666 @@
-use std::io::{Error, ErrorKind};
+use std::collections::HashMap;
+use std::io::Error;
 use std::path::PathBuf;
 use std::time::Duration;
 
-use noreya_sdbp::*;
+use crossbeam_channel::Sender;
 use noreya_sdbp::drv::api::{Error as ApiError, IntoBytes, Tag, TlvValue};
 use noreya_sdbp::drv::core::*;
-use noreya_sdbp::powermgmt::manager::{PowerManager};
-use noreya_sdbp::sdbp::*;
-use noreya_sdbp::sdbp::response::SdbpResponse;
 use noreya_sdbp::util::*;
-use sdbp::request::custom::io::IoBuilder;
-use sdbp::response::custom::io::powermgmt::SetPowerConfig as SetPowerConfigResponse;
-use sdbp::response::custom::io::powermgmt::TestPowerConfig as TestPowerConfigResponse;
 
-use crate::powermgmt::data::PowerConfig;
+use crate::powermgmt::audit::{AuditLog, AuditRecord, AuditSlot};
+use crate::powermgmt::client::{Connection, Connector, Health, Maintenance, PowerMgmtClient, Shortfall, SocketConnector};
+use crate::powermgmt::data::{PowerBudget, PowerConfig, PowerRequest, RequestFrame};
+use crate::powermgmt::error::PowerMgmtError;
+use crate::powermgmt::module::{ModuleInfo, Modules, SdbpModules};
+use crate::powermgmt::pool::WorkerPool;
+use crate::powermgmt::replies::Replies;
+use crate::powermgmt::slots::SlotResolver;
+use crate::powermgmt::store::ConfigStore;
 
 use super::settings;
-use std::sync::Mutex;
+use super::watchdog;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use std::thread;
 
-mod data;
+mod audit;
+mod client;
+pub mod data;
+mod error;
 mod helper;
+mod module;
+mod pool;
+mod replies;
+mod retry;
+mod slots;
+mod store;
+#[cfg(test)]
+mod testing;
+#[cfg(test)]
+pub(crate) use testing::install_settings;
 
-pub struct PowerMgmt<'a, 'b> {
-    vdev_id: u16,
-    dev_pair: &'a ChannelPair<PMsg>,
-    shared: &'b mut SharedStats,
+/// Device id of the PowerMgmt virtual device
+pub const VDEV_ID: u16 = 0x2001;
+
+static STOPPED: AtomicBool = AtomicBool::new(false);
+static RELOAD: AtomicBool = AtomicBool::new(false);
+
+/// Makes the PowerMgmt thread reload the persisted power configs and apply changed ones.
+pub fn request_reload() {
+    RELOAD.store(true, Ordering::SeqCst);
+}
+
+/// Returns true once the PowerMgmt thread finished its last request.
+pub fn is_stopped() -> bool {
+    STOPPED.load(Ordering::SeqCst)
 }
 
-impl<'a, 'b> PowerMgmt<'a, 'b> {
-    pub fn new(vdev_id: u16, dev_pair: &'a ChannelPair<PMsg>, shared: &'b mut SharedStats) -> PowerMgmt<'a, 'b> {
-        return PowerMgmt { vdev_id, dev_pair, shared };
+/// Pin config the driver applied to a slot, together with the module it was applied to.
+struct CommittedConfig {
+    uid: String,
+    config: PowerConfig,
+    /// Budget reserved from power-mgmt
+    budget: PowerBudget,
+}
+
+/// State shared by the workers of the PowerMgmt virtual device.
+struct PowerMgmtState {
+    committed: Mutex<HashMap<u8, CommittedConfig>>,
+    store: Mutex<ConfigStore>,
+    slots: Mutex<Box<dyn SlotResolver>>,
+    audit: Mutex<AuditLog>,
+    modules: Arc<dyn Modules>,
+    /// Idle power per module UID, read from the descriptor while the module was suspended
+    idle: Mutex<HashMap<String, ModuleInfo>>,
+    /// Serializes the requests for a slot
+    slot_locks: Mutex<HashMap<u8, Arc<Mutex<()>>>>,
+    /// Only held while talking to power-mgmt
+    power_mgmt: Mutex<PowerMgmtClient>,
+    /// Held from reserving a budget until it is finished or abandoned, power-mgmt checks a request
+    /// against the finished budgets only, so overlapping negotiations could exceed the available power
+    negotiation: Mutex<()>,
+    /// Health of the power-mgmt client, readable while the client is held
+    health: Arc<Mutex<Health>>,
+    in_flight: AtomicUsize,
+}
+
+impl PowerMgmtState {
+    /// State for the modules of the driver, everything else comes from the settings.
+    fn new(modules: Arc<dyn Modules>) -> PowerMgmtState {
+        let current = settings::current();
+        let store = ConfigStore::open(PathBuf::from(&current.state_dir).join(settings::POWER_CONFIG_STATE_FILE));
+        PowerMgmtState::with(modules, slots::from_settings(&current), Box::new(SocketConnector), store, AuditLog::new(&current.audit))
     }
 
-    fn parse(msg: &PMsg) -> Result<data::PowerConfig, Error> {
-        let request = msg.get_msg().expect("Communication partner is dead!");
-        let config = data::PowerConfig::new(request);
+    fn with(modules: Arc<dyn Modules>, slots: Box<dyn SlotResolver>, connector: Box<dyn Connector>, store: ConfigStore, audit: AuditLog) -> PowerMgmtState {
+        let health = Arc::new(Mutex::new(Health::Unknown));
+        PowerMgmtState {
+            committed: Mutex::new(HashMap::new()),
+            store: Mutex::new(store),
+            slots: Mutex::new(slots),
+            audit: Mutex::new(audit),
+            modules,
+            idle: Mutex::new(HashMap::new()),
+            slot_locks: Mutex::new(HashMap::new()),
+            power_mgmt: Mutex::new(PowerMgmtClient::new(connector, health.clone())),
+            health,
+            negotiation: Mutex::new(()),
+            in_flight: AtomicUsize::new(0),
+        }
+    }
 
-        let config = match config {
-            Err(err) => return Err(err),
-            Ok(value) => value,
-        };
-        return Ok(config);
+    fn reload(&self) {
+        let current = settings::current();
+        let path = PathBuf::from(&current.state_dir).join(settings::POWER_CONFIG_STATE_FILE);
+        info!("Reloading power configs from {}", path.display());
+        *self.store.lock().expect("Could not lock store") = ConfigStore::open(path);
+        *self.slots.lock().expect("Could not lock slots") = slots::from_settings(&current);
+        *self.audit.lock().expect("Could not lock audit log") = AuditLog::new(&current.audit);
     }
 
+    fn slot_lock(&self, slot: u8) -> Arc<Mutex<()>> {
+        self.slot_locks.lock().expect("Could not lock slot locks").entry(slot).or_default().clone()
+    }
 
-    fn suspend_device(&mut self, dev_id: u16) -> Result<(), Error> {
-        let cmd_mode_suspend = CoreBuilder::new().control().mode_suspend().expect("Could not build cmd");
+    /// Returns true while a request for the slot is running.
+    fn is_busy(&self, slot: u8) -> bool {
+        self.slot_lock(slot).try_lock().is_err()
+    }
 
-        let dev_msg = PMsg::create(self.vdev_id, dev_id as u16, Ok(cmd_mode_suspend));
-        match self.dev_pair.tx().send(dev_msg) {
-            Ok(_) => (),
-            Err(err) => {
-                error!("{}",err);
-                return Err(Error::new(ErrorKind::BrokenPipe, format!("Sending command to slot {} failed", dev_id)));
-            }
-        };
+    fn committed_config(&self, slot: u8) -> Option<PowerConfig> {
+        self.committed.lock().expect("Could not lock committed configs").get(&slot).map(|active| active.config.clone())
+    }
 
-        match self.dev_pair.rx().recv_timeout(Duration::from_millis(1000)) {
-            Ok(_) => (),
-            Err(err) => {
-                error!("{}",err);
-                return Err(Error::new(ErrorKind::BrokenPipe, format!("Receiving from slot {} failed", dev_id)));
+    fn committed_budget(&self, slot: u8) -> Option<PowerBudget> {
+        self.committed.lock().expect("Could not lock committed configs").get(&slot).map(|active| active.budget)
+    }
+}
+
+/// Runs on the PowerMgmt thread: hands module replies to the waiting worker
+/// and queues every client request for the worker pool.
+struct Router {
+    tx: Sender<PMsg>,
+    state: Arc<PowerMgmtState>,
+    replies: Arc<Replies>,
+    pool: WorkerPool,
+    present: HashMap<u8, String>,
+}
+
+impl Router {
+    /// Messages from a module are replies, everything else is a client request.
+    ///
+    /// Modules are addressed by their slot, the dispatcher gives clients ids above the slot range,
+    /// so a reply nobody waits for is discarded instead of being handled as request.
+    /// Probes of the watchdog are answered right away, they show that this thread routes messages.
+    fn route(&mut self, msg: PMsg) {
+        let src = msg.get_src();
+        if src == watchdog::PROBE_VDEV_ID {
+            match self.tx.send(PMsg::create(msg.get_dst(), src, Ok(watchdog::PROBE.to_vec()))) {
+                Err(_) => error!("Error while answering watchdog probe"),
+                _ => (),
             }
-        };
-        return Ok(());
+            return;
+        }
+        match src <= u8::MAX as u16 {
+            true => self.replies.deliver(msg),
+            false => {
+                let tx = self.tx.clone();
+                let dst = msg.get_dst();
+                let versioned = msg.get_msg().and_then(|frame| RequestFrame::versioned_header(&frame));
+                let slots = match msg.get_msg().map(RequestFrame::parse) {
+                    Some(Ok(frame)) => frame.request.slots(),
+                    _ => Vec::new(), // Rejected by the worker without touching a slot
+                };
+                let submitted = self.pool.submit(slots, Box::new(move |worker| {
+                    let res = worker.execute(&msg);
+                    match tx.send(res) {
+                        Err(_) => error!("Error while sending response for to client"),
+                        _ => (),
+                    }
+                }));
+                if submitted.is_err() {
+                    warn!("Refusing request from {}: too many pending requests", src);
+                    let err = PowerMgmtError::Internal { reason: "Too many pending requests".to_string() };
+                    match self.tx.send(PowerMgmt::respond(dst, src, versioned, Err(err.into()))) {
+                        Err(_) => error!("Error while sending response for to client"),
+                        _ => (),
+                    }
+                }
+            }
+        }
     }
 
-    fn update_descriptor(&mut self, dev_id: u16) -> Result<(), Error> {
-        let mut helper = match helper::PowerMgmtHelper::new(dev_id, &mut self.shared) {
-            Ok(value) => value,
-            Err(err) => return Err(err),
+    /// Starts reconnecting to power-mgmt or registering the budgets again after it restarted.
+    fn maintain_power_mgmt(&self) {
+        let work = match self.state.power_mgmt.try_lock() {
+            Ok(mut client) => client.maintenance(),
+            Err(_) => None, // The client is talking to power-mgmt, checked again on the next call
         };
-
-        let request = sdbp::request::core::control::ControlBuilder::new().update_descriptor().expect("Could not build cmd");
-        let device_msg = PMsg::create(self.vdev_id, dev_id as u16, Ok(request));
-        match self.dev_pair.tx().send(device_msg) {
-            Ok(_) => (),
-            Err(err) => {
-                error!("{}",err);
-                return Err(Error::new(ErrorKind::BrokenPipe, format!("Sending command to slot {} failed", dev_id)));
+        if let Some(work) = work {
+            if self.pool.submit(Vec::new(), Box::new(move |worker| worker.maintain(work))).is_err() {
+                self.state.power_mgmt.lock().expect("Could not lock power-mgmt client").maintained(false); // Retried on the next call
             }
         }
+    }
 
-        match self.dev_pair.rx().recv_timeout(Duration::from_millis(1000)) {
-            Ok(_) => (),
-            Err(err) => {
-                error!("{}",err);
-                return Err(Error::new(ErrorKind::BrokenPipe, format!("Receiving from slot {} failed", dev_id)));
+    /// Starts a replay of the persisted power config for modules that (re)appeared since the last call.
+    fn restore_reconnected(&mut self) {
+        let present = self.state.modules.present();
+
+        if RELOAD.swap(false, Ordering::SeqCst) {
+            self.state.reload();
+            self.present.clear(); // Re-check every module against the reloaded configs
+        }
+
+        let previous = std::mem::replace(&mut self.present, present.clone());
+        for (adr, uid) in &previous {
+            if present.contains_key(adr) {
+                continue;
+            }
+            if self.state.is_busy(*adr) {
+                self.present.insert(*adr, uid.clone()); // Modules disconnect while a request suspends them
+                continue;
             }
+            self.state.committed.lock().expect("Could not lock committed configs").remove(adr); // Budget of a removed module is released by power-mgmt
         }
-        match helper.wait_for_update_descriptor(&mut self.shared, Duration::from_millis(600)) {
-            Ok(_) => {}
-            Err(_) => {
-                debug!("Descriptor did not change")
+
+        for (adr, uid) in present {
+            if previous.get(&adr) == Some(&uid) {
+                continue;
+            }
+            if self.state.store.lock().expect("Could not lock store").get(&uid).is_none() {
+                continue;
+            }
+            let slot = adr;
+            if self.pool.submit(vec![slot], Box::new(move |worker| worker.restore(slot, uid))).is_err() {
+                self.present.remove(&slot); // Retried on the next call
             }
         }
-        return Ok(());
     }
+}
 
-    fn update_config(&mut self, conifg: &mut PowerConfig) -> Result<(), Error> {
-        let helper = match helper::PowerMgmtHelper::new(conifg.get_device_id() as u16, &mut self.shared) {
-            Ok(value) => value,
-            Err(err) => return Err(err),
+/// Handles one request of the PowerMgmt virtual device on a worker thread.
+pub struct PowerMgmt {
+    state: Arc<PowerMgmtState>,
+}
+
+impl PowerMgmt {
+    fn new(state: Arc<PowerMgmtState>) -> PowerMgmt {
+        return PowerMgmt { state };
+    }
+
+    fn parse(src: u16, request: Option<Vec<u8>>) -> Result<RequestFrame, Error> {
+        let request = match request {
+            Some(value) => value,
+            None => return Err(PowerMgmtError::Internal { reason: format!("Request from {} without message", src) }.into()),
         };
+        let request = RequestFrame::parse(request);
 
-        let device = helper.get_descriptor();
+        let request = match request {
+            Err(err) => return Err(err.into()),
+            Ok(value) => value,
+        };
+        return Ok(request);
+    }
 
-        conifg.set_idle_power_3v3(device.max_power_3v3());
-        //conifg.set_idle_power_5v0(device.max_power_5v());
-        //conifg.set_idle_power_12v(device.max_power_12v());
+    /// Descriptor of the module in a slot, the slot resolver decides whether the slot is connected.
+    fn module_info(&self, slot: u8) -> Result<ModuleInfo, Error> {
+        match self.state.slots.lock().expect("Could not lock slots").check_connected(slot) {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        }
+        self.state.modules.info(slot)
+    }
 
-        Ok(())
+    fn suspend_device(&self, slot: u8) -> Result<(), Error> {
+        let policy = settings::current().retry.suspend.clone();
+        retry::run("suspend", slot, &policy, || self.state.modules.suspend(slot))
     }
 
+    fn update_descriptor(&self, slot: u8) -> Result<ModuleInfo, Error> {
+        let policy = settings::current().retry.descriptor.clone();
+        retry::run("update descriptor", slot, &policy, || self.state.modules.update_descriptor(slot))
+    }
 
     fn test_power_config(&self, config: &PowerConfig) -> Result<(), Error> {
-        debug!("Slot {}: test power config",config.get_device_id());
-        let cmd_test_pwr_config = match IoBuilder::new().powermgmt().test_power_config(config.pin_vec()) {
+        let policy = settings::current().retry.test.clone();
+        retry::run("test power config", config.get_device_id(), &policy, || self.test_power_config_once(config))
+    }
+
+    /// Retrying is safe, the module only takes over the pins and the budget is reserved once before.
+    fn set_power_config(&self, config: &PowerConfig) -> Result<(), Error> {
+        let policy = settings::current().retry.set.clone();
+        retry::run("set power config", config.get_device_id(), &policy, || self.set_power_config_once(config))
+    }
+
+    /// Sets the idle power of every rail.
+    ///
+    /// A suspended module reports its idle power in the descriptor, the load of its pins is not included.
+    /// So the baseline is the descriptor read after the module was suspended and the settle time passed,
+    /// it does not depend on which config the driver believes is active. A module that keeps running
+    /// (`suspended` is false) reports its pin load as well, then the baseline read during its last suspend
+    /// is used, or the running descriptor, which overestimates the idle power, if it was never suspended.
+    fn update_config(&self, conifg: &mut PowerConfig, suspended: bool) -> Result<(), Error> {
+        let device = match self.module_info(conifg.get_device_id()) {
             Ok(value) => value,
-            Err(err) => {
-                return Err(err);
-            }
+            Err(err) => return Err(err),
         };
 
-        let device_msg = PMsg::create(self.vdev_id, config.get_device_id() as u16, Ok(cmd_test_pwr_config));
-        match self.dev_pair.tx().send(device_msg) {
-            Ok(_) => (),
-            Err(_err) => {
-                return Err(Error::new(ErrorKind::BrokenPipe, format!("Sending command to slot {} failed", config.get_device_id())));
+        let mut idle = self.state.idle.lock().expect("Could not lock idle power");
+        let device = match suspended {
+            true => {
+                idle.insert(device.uid.clone(), device.clone());
+                device
             }
+            false => idle.get(&device.uid).cloned().unwrap_or(device),
+        };
+        drop(idle);
+        debug!("Slot {}: idle power 3v3: {} 5v0: {} 12v: {}", conifg.get_device_id(), device.max_power_3v3, device.max_power_5v0, device.max_power_12v);
+
+        conifg.set_idle_power_3v3(device.max_power_3v3);
+        conifg.set_idle_power_5v0(device.max_power_5v0);
+        conifg.set_idle_power_12v(device.max_power_12v);
+
+        Ok(())
+    }
+
+    fn test_power_config_once(&self, config: &PowerConfig) -> Result<(), Error> {
+        let slot = config.get_device_id();
+        let status = match self.state.modules.test_power_config(slot, &config.pin_vec()) {
+            Ok(value) => value,
+            Err(err) => return Err(err),
+        };
+
+        if status != 0 {
+            error!("Error in response test_power_config from slot {}: status {}", slot, status);
+            return Err(PowerMgmtError::ConfigRejected { slot, status }.into());
         }
+        Ok(())
+    }
 
-        let response = match self.dev_pair.rx().recv_timeout(Duration::from_millis(1000)) {
+    fn set_power_config_once(&self, config: &PowerConfig) -> Result<(), Error> {
+        let slot = config.get_device_id();
+        let status = match self.state.modules.set_power_config(slot, &config.pin_vec()) {
             Ok(value) => value,
-            Err(_err) => {
-                return Err(Error::new(ErrorKind::BrokenPipe, format!("Receiving from slot {} failed", config.get_device_id())));
-            }
+            Err(err) => return Err(err),
         };
 
-        let resp = match response.get_msg() {
-            None => {
-                return Err(Error::new(ErrorKind::BrokenPipe, format!("Could not get message from slot {}", config.get_device_id())));
-            }
-            Some(val) => { val }
+        if status != 0 {
+            error!("Error in response set_power_config from slot {}: status {}", slot, status);
+            return Err(PowerMgmtError::ConfigRejected { slot, status }.into());
+        }
+        Ok(())
+    }
+
+    /// Returns false if the requested pins are already active on the module in the slot.
+    fn is_update_necessary(&self, conf: &PowerConfig) -> Result<bool, Error> {
+        let uid = match self.module_info(conf.get_device_id()) {
+            Ok(value) => value.uid,
+            Err(err) => return Err(err),
+        };
+
+        let committed = self.state.committed.lock().expect("Could not lock committed configs");
+        let result = match committed.get(&conf.get_device_id()) {
+            Some(active) => active.uid != uid || active.config.to_payload() != conf.to_payload(),
+            None => true,
         };
+        return Ok(result);
+    }
 
-        let pm_test_response = match TestPowerConfigResponse::from_raw(resp) {
+    /// Registers the budget a slot held before with power-mgmt again.
+    fn restore_budget(&self, client: &mut PowerMgmtClient, slot: u8, budget: &PowerBudget) -> Result<(), Error> {
+        let (power_3v3, power_5v0, power_12v) = match budget.to_wire() {
             Ok(value) => value,
+            Err(err) => return Err(err.into()),
+        };
+
+        let mut con_pm = match client.reserve(slot, (power_3v3, power_5v0, power_12v)) {
+            Ok((con_pm, None)) => con_pm,
+            Ok((_, Some(shortfall))) => return Err(PowerMgmtError::BudgetExceeded { slot, shortfall }.into()),
+            Err(err) => return Err(err),
+        };
+
+        match con_pm.finish_request() {
+            Ok(true) => {
+                client.release(con_pm);
+                Ok(())
+            }
+            Ok(false) => Err(PowerMgmtError::PowerMgmtUnreachable { reason: format!("Finishing previous power config of slot {} failed", slot) }.into()),
             Err(err) => {
-                return Err(Error::new(ErrorKind::BrokenPipe, format!("Parsing from slot {} failed: {}", config.get_device_id(), err)));
+                client.failed();
+                Err(PowerMgmtError::PowerMgmtUnreachable { reason: format!("Restoring budget of slot {} failed: {}", slot, err) }.into())
             }
+        }
+    }
+
+    /// Re-registers the committed budget of a slot whose rolled back request was already finished.
+    ///
+    /// A slot without committed config gets an empty budget, which releases the finished one.
+    fn restore_committed(&self, client: &mut PowerMgmtClient, slot: u8) {
+        let budget = self.state.committed_budget(slot).unwrap_or_default();
+        match self.restore_budget(client, slot, &budget) {
+            Ok(_) => (),
+            Err(err) => error!("Slot {}: restoring power budget failed: {}", slot, err),
+        }
+    }
+
+    /// Registers every budget in the ledger with power-mgmt again after it restarted.
+    fn reregister(&mut self) -> bool {
+        let budgets: Vec<(u8, PowerBudget)> = {
+            let committed = self.state.committed.lock().expect("Could not lock committed configs");
+            committed.iter().map(|(slot, active)| (*slot, active.budget)).collect()
         };
+        let state = self.state.clone();
+        let _negotiation = state.negotiation.lock().expect("Could not lock negotiation");
+        let mut client = state.power_mgmt.lock().expect("Could not lock power-mgmt client");
 
-        if pm_test_response.status != 0 {
-            error!("Error in response test_power_config from slot {}",config.get_device_id());
-            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid power config")));
+        let mut reregistered = true;
+        for (slot, budget) in budgets {
+            match self.restore_budget(&mut client, slot, &budget) {
+                Ok(_) => info!("Slot {}: budget registered with power-mgmt again", slot),
+                Err(err) => {
+                    error!("Slot {}: registering budget with restarted power-mgmt failed: {}", slot, err);
+                    reregistered = false;
+                }
+            }
         }
-        Ok(())
+        reregistered
     }
 
-    fn set_power_config(&self, config: &PowerConfig) -> Result<(), Error> {
-        trace!("Slot {}: set power config",config.get_device_id());
-        let cmd_set_pwr_config = match IoBuilder::new().powermgmt().set_power_config(config.pin_vec()) {
-            Ok(value) => value,
-            Err(err) => {
-                error!("{}", err);
-                return Err(err);
+    /// Tries to reach power-mgmt again, or registers the held budgets after a restart.
+    fn maintain(&mut self, work: Maintenance) {
+        let reregistered = match work {
+            Maintenance::Reregister => self.reregister(),
+            Maintenance::Reconnect => {
+                let mut client = self.state.power_mgmt.lock().expect("Could not lock power-mgmt client");
+                if let Ok(con_pm) = client.connect() {
+                    client.release(con_pm);
+                }
+                false
             }
         };
+        self.state.power_mgmt.lock().expect("Could not lock power-mgmt client").maintained(reregistered);
+    }
 
-        let device_msg = PMsg::create(self.vdev_id, config.get_device_id() as u16, Ok(cmd_set_pwr_config));
+    /// Undoes the steps of a failed power config request so the slot is left as it was before.
+    ///
+    /// `reservation` is the power-mgmt connection holding an unfinished reservation (if any),
+    /// `restore_pins` is set once the new pin config may have reached the module.
+    ///
+    /// Closing the connection without `finish_request` abandons the reservation, the budget
+    /// finished before for the slot stays registered, so power-mgmt is not contacted again.
+    fn rollback(&mut self, slot: u8, reservation: Option<Box<dyn Connection>>, restore_pins: bool) {
+        warn!("Slot {}: rolling back power config", slot);
+        drop(reservation);
 
-        match self.dev_pair.tx().send(device_msg) {
-            Ok(_) => (),
-            Err(err) => {
-                error!("{}",err);
-                return Err(Error::new(ErrorKind::BrokenPipe, format!("Sending command to slot {} failed", config.get_device_id())));
+        if restore_pins {
+            let previous = match self.state.committed_config(slot) {
+                Some(config) => config,
+                None => PowerConfig::from_pins(slot, Vec::new()).expect("Empty pin config is valid"), // Default config of the module
+            };
+            match self.set_power_config(&previous) {
+                Ok(_) => (),
+                Err(err) => error!("Slot {}: rollback of pin config failed: {}", slot, err),
             }
         }
 
-        let response = match self.dev_pair.rx().recv_timeout(Duration::from_millis(1000)) {
+        // Resumes the module
+        match self.update_descriptor(slot) {
+            Ok(_) => (),
+            Err(err) => error!("Slot {}: resuming module failed: {}", slot, err),
+        }
+    }
+
+    /// Runs the module test and the power-mgmt budget check without suspending the module or committing anything.
+    ///
+    /// The reservation is never finished, dropping its connection abandons it and leaves
+    /// the budget power-mgmt holds for the slot untouched.
+    fn validate(&mut self, mut cmd: PowerConfig) -> Result<Shortfall, Error> {
+        let slot = cmd.get_device_id();
+
+        match self.update_config(&mut cmd, false) {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        }
+
+        debug!("test_power_config");
+        match self.test_power_config(&cmd) {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        }
+
+        let (power_3v3, power_5v0, power_12v) = match cmd.budget().and_then(|budget| budget.to_wire()) {
+            Ok(value) => value,
+            Err(err) => return Err(err.into()),
+        };
+
+        let state = self.state.clone();
+        let _negotiation = state.negotiation.lock().expect("Could not lock negotiation");
+        let mut client = state.power_mgmt.lock().expect("Could not lock power-mgmt client");
+        debug!("Validate 3v3: {:?} 5v0: {:?} 12v: {:?}",power_3v3,power_5v0,power_12v);
+        let (con_pm, shortfall) = match client.reserve(slot, (power_3v3, power_5v0, power_12v)) {
             Ok(value) => value,
             Err(err) => {
-                error!("{}",err);
-                return Err(Error::new(ErrorKind::BrokenPipe, format!("Receiving from slot {} failed", config.get_device_id())));
+                return Err(err);
             }
         };
 
-        let msg = match response.get_msg() {
-            None => {
-                return Err(Error::new(ErrorKind::BrokenPipe, format!("Could not get message from slot {}", config.get_device_id())));
-            }
-            Some(val) => {val}
+        drop(con_pm); // Abandons the reservation
+        return Ok(shortfall.unwrap_or((0, 0, 0)));
+    }
+
+    /// Returns the pin config last applied to a slot and its budget as u16, see [`PowerRequest::Query`].
+    fn query(&self, slot: u8) -> Result<Vec<u8>, Error> {
+        let active = match self.state.committed_config(slot) {
+            Some(value) => value,
+            None => return Err(PowerMgmtError::NotConfigured { slot }.into()),
         };
 
-        let pm_set_response = match SetPowerConfigResponse::from_raw(msg) {
+        let (power_3v3, power_5v0, power_12v) = match active.budget().and_then(|budget| budget.to_wire()) {
             Ok(value) => value,
-            Err(err) => {
-                error!("{}", err);
-                return Err(Error::new(ErrorKind::BrokenPipe, format!("Parsing Response (SetPowerConfigResponse) from slot {} failed", config.get_device_id())));
-            }
+            Err(err) => return Err(err.into()),
         };
 
-        if pm_set_response.status != 0 {
-            error!("Error in response set_power_config from slot {}",config.get_device_id());
-            error!("CODE: {}",pm_set_response.status);
-            return Err(Error::new(ErrorKind::InvalidInput, format!("Invalid power config")));
+        let mut response: Vec<u8> = Vec::new();
+        response.extend(power_3v3.to_be_bytes());
+        response.extend(power_5v0.to_be_bytes());
+        response.extend(power_12v.to_be_bytes());
+        response.extend(active.to_payload());
+        return Ok(response);
+    }
+
+    /// Returns the budget reserved by every slot and the totals per rail as u32, see [`PowerRequest::Ledger`].
+    fn ledger(&self) -> Vec<u8> {
+        let committed = self.state.committed.lock().expect("Could not lock committed configs");
+        let mut slots: Vec<u8> = committed.keys().copied().collect();
+        slots.sort_unstable();
+
+        let mut total = PowerBudget::default();
+        let mut response: Vec<u8> = vec![slots.len() as u8];
+        for slot in slots {
+            let budget = committed[&slot].budget;
+            total = total.saturating_add(&budget);
+            response.push(slot);
+            response.extend(PowerMgmt::encode_budget(&budget));
         }
-        Ok(())
+        response.extend(PowerMgmt::encode_budget(&total));
+        response
     }
 
-    // fn is_update_necessary(&mut self, conf: &PowerConfig) -> Result<bool,Error> {
-    //
-    //     let mut helper = match helper::PowerMgmtHelper::new(conf.get_device_id() as u16, &mut self.shared) {
-    //         Ok(value) => value,
-    //         Err(err) => return Err(err),
-    //     };
-    //
-    //     let desc = helper.get_descriptor();
-    //
-    //     let mut result = true;
-    //
-    //     if  desc.max_power_5v() == conf.get_power_5v5() &&
-    //         desc.max_power_12v() == conf.get_power_12v() {
-    //         result = false;
-    //     }
-    //     return Ok(result)
-    //
-    // }
-
-    fn power_management(&mut self, msg: &PMsg) -> Result<(u16,u16,u16), Error> {
-        let mut cmd = match PowerMgmt::parse(&msg) {
+    fn encode_budget(budget: &PowerBudget) -> Vec<u8> {
+        let mut response: Vec<u8> = Vec::new();
+        response.extend(budget.power_3v3.0.to_be_bytes());
+        response.extend(budget.power_5v0.0.to_be_bytes());
+        response.extend(budget.power_12v.0.to_be_bytes());
+        response
+    }
+
+    /// Encodes the power that exceeds the available budget per rail, all zero on success.
+    fn encode_shortfall(result: Shortfall) -> Vec<u8> {
+        let mut response: Vec<u8> = Vec::new();
+        response.extend(result.0.to_be_bytes());
+        response.extend(result.1.to_be_bytes());
+        response.extend(result.2.to_be_bytes());
+        response
+    }
+
+    /// Handles the request of client `src` and returns the response payload.
+    fn power_management(&mut self, src: u16, request: Option<Vec<u8>>) -> Result<Vec<u8>, Error> {
+        let frame = match PowerMgmt::parse(src, request) {
             Ok(value) => value,
             Err(err) => {
                 return Err(err);
             }
         };
 
-        let path = PathBuf::from(format!("/sys/class/sdbp/slot{}", cmd.get_device_id()));
-        if !path.as_path().exists() {
-            return Err(Error::new(ErrorKind::NotConnected, format!("Slot {} not connected", cmd.get_device_id())));
+        // The pool runs one job per slot at a time, the locks guard against other callers.
+        // Locking in ascending order keeps overlapping bulk requests from deadlocking.
+        let slot_locks: Vec<Arc<Mutex<()>>> = frame.request.slots().into_iter().map(|slot| self.state.slot_lock(slot)).collect();
+        let _slots: Vec<_> = slot_locks.iter().map(|lock| lock.lock().expect("Could not lock slot")).collect();
+
+        let (version, command) = (frame.version, frame.request.command());
+        let response = match frame.request {
+            PowerRequest::Apply(cmd) => {
+                let audit = vec![AuditSlot::new(self.state.committed_config(cmd.get_device_id()).as_ref(), &cmd)];
+                let response = self.apply(cmd).map(PowerMgmt::encode_shortfall);
+                self.audit(Some(src), "set", audit, &response);
+                response
+            }
+            PowerRequest::Validate(cmd) => self.validate(cmd).map(PowerMgmt::encode_shortfall),
+            PowerRequest::Query(slot) => self.query(slot),
+            PowerRequest::Bulk(configs) => {
+                let audit = configs.iter().map(|config| AuditSlot::new(self.state.committed_config(config.get_device_id()).as_ref(), config)).collect();
+                let response = self.apply_bulk(configs);
+                self.audit(Some(src), "bulk", audit, &response);
+                response
+            }
+            PowerRequest::Capabilities => Ok(data::capabilities()),
+            PowerRequest::Ledger => Ok(self.ledger()),
+            PowerRequest::Health => Ok(self.state.health.lock().expect("Could not lock health").to_wire()),
+        };
+        response.map(|payload| RequestFrame::encode_response(version, command, payload))
+    }
+
+    fn apply(&mut self, mut cmd: PowerConfig) -> Result<Shortfall, Error> {
+        let slot = cmd.get_device_id();
+
+        match self.is_update_necessary(&cmd) {
+            Ok(true) => (),
+            Ok(false) => {
+                debug!("Slot {}: power config already active", slot);
+                return Ok((0,0,0));
+            }
+            Err(err) => return Err(err),
         }
 
-        match self.suspend_device(cmd.get_device_id() as u16) {
+        match self.suspend_device(slot) {
             Ok(_) => (), // Note: This triggers also update_descriptor
-            Err(err) => return Err(err),
+            Err(err) => {
+                self.rollback(slot, None, false);
+                return Err(err);
+            }
         }
-        thread::sleep(Duration::from_millis(100)); // Implicit update_descriptor is async
+        thread::sleep(settings::current().timeouts.suspend_settle()); // Implicit update_descriptor is async
 
-        match self.update_config(&mut cmd) {
+        match self.update_config(&mut cmd, true) {
             Ok(_) => (),
-            Err(err) => return Err(err),
+            Err(err) => {
+                self.rollback(slot, None, false);
+                return Err(err);
+            }
         }
 
         debug!("test_power_config");
         match self.test_power_config(&cmd) {
             Ok(_) => (),
             Err(err) => {
+                self.rollback(slot, None, false);
                 return Err(err);
             }
         }
 
-        let mut con_pm = match PowerManager::new(settings::POWER_MGMT_PATH.to_string(), Some(Duration::from_secs(1))) {
+        let (power_3v3, power_5v0, power_12v) = match cmd.budget().and_then(|budget| budget.to_wire()) {
             Ok(value) => value,
-            Err(err) => return Err(err),
+            Err(err) => {
+                self.rollback(slot, None, false);
+                return Err(err.into());
+            }
         };
-        debug!("3v3: {:?} 5v0: {:?} 12v: {:?}",cmd.get_power_3v3(),cmd.get_power_5v5(),cmd.get_power_12v());
-        let response = con_pm.request(cmd.get_device_id(), cmd.get_power_3v3(), cmd.get_power_5v5(), cmd.get_power_12v());
-        match response {
-            Ok(response) => {
-                match response.successful {
-                    true => {},
-                    false => {
-                        return Ok((response.to_much_power_3v3,response.to_much_power_5v0,response.to_much_power_12v));
-                    }
-                }
+
+        debug!("3v3: {:?} 5v0: {:?} 12v: {:?}",power_3v3,power_5v0,power_12v);
+        let state = self.state.clone();
+        let _negotiation = state.negotiation.lock().expect("Could not lock negotiation"); // Until finished or rolled back
+        let reserved = self.state.power_mgmt.lock().expect("Could not lock power-mgmt client").reserve(slot, (power_3v3, power_5v0, power_12v));
+        let mut con_pm = match reserved {
+            Ok((con_pm, None)) => con_pm,
+            Ok((_, Some(shortfall))) => {
+                self.rollback(slot, None, false);
+                return Ok(shortfall);
             }
             Err(err) => {
-                return Err(Error::new(ErrorKind::ConnectionAborted, format!("Sending test_power_config to slot {} failed: {:?}", cmd.get_device_id(),err)));
+                self.rollback(slot, None, false);
+                return Err(err);
             }
         };
 
@@ -286,89 +668,836 @@ impl<'a, 'b> PowerMgmt<'a, 'b> {
         debug!("set_power_config");
         match self.set_power_config(&cmd) {
             Ok(_) => (),
-            Err(err) => return Err(err),
+            Err(err) => {
+                self.rollback(slot, Some(con_pm), true);
+                return Err(err);
+            }
         }
 
         debug!("update_descriptor");
-        match self.update_descriptor(cmd.get_device_id() as u16) {
-            Ok(_) => (),
-
+        let descriptor = match self.update_descriptor(slot) {
+            Ok(value) => value,
             Err(err) => {
+                self.rollback(slot, Some(con_pm), true);
                 return Err(err);
             }
-        }
+        };
 
         debug!("finish request");
+        let mut client = state.power_mgmt.lock().expect("Could not lock power-mgmt client");
         let response = con_pm.finish_request();
         match response {
-            Ok(response) => {
-                match response.successful {
-                    true => (),
-                    false => {
-                        error!("FINISH ERROR");
-                        return Err(Error::new(ErrorKind::InvalidInput, format!("finish power config failed")));
-                    }
-                }
+            Ok(true) => (),
+            Ok(false) => {
+                error!("FINISH ERROR");
+                drop(client);
+                self.rollback(slot, Some(con_pm), true);
+                return Err(PowerMgmtError::PowerMgmtUnreachable { reason: format!("Finishing power config of slot {} failed", slot) }.into());
             }
             Err(err) => {
-                return Err(Error::new(ErrorKind::ConnectionAborted, format!("Sending set_power_config to slot {} failed: {:?}", cmd.get_device_id(),err)));
+                client.failed();
+                drop(client);
+                self.rollback(slot, Some(con_pm), true);
+                return Err(PowerMgmtError::PowerMgmtUnreachable { reason: format!("Finishing power config of slot {} failed: {}", slot, err) }.into());
             }
         };
+        client.release(con_pm);
+        drop(client);
 
+        self.commit(cmd, &descriptor);
         return Ok((0,0,0));
     }
 
+    /// Remembers and persists a config that was applied successfully.
+    fn commit(&self, config: PowerConfig, descriptor: &ModuleInfo) {
+        let slot = config.get_device_id();
+        let uid = descriptor.uid.clone();
+        let budget = config.budget().unwrap_or_default(); // Already checked before it was sent to power-mgmt
+        match self.state.store.lock().expect("Could not lock store").set(uid.clone(), config.to_payload()) {
+            Ok(_) => (),
+            Err(err) => error!("Slot {}: could not persist power config: {}", slot, err),
+        }
+        self.state.committed.lock().expect("Could not lock committed configs").insert(slot, CommittedConfig { uid, config, budget });
+    }
+
+    /// Rolls back every slot of a bulk request, see [`PowerMgmt::rollback`].
+    ///
+    /// `reservations` holds the connections with the reservations of the first configs,
+    /// the pins of the first `restore_pins` configs may have reached their module.
+    fn rollback_bulk(&mut self, configs: &[PowerConfig], reservations: Vec<Box<dyn Connection>>, restore_pins: usize) {
+        let mut reservations = reservations.into_iter();
+        for (index, config) in configs.iter().enumerate() {
+            self.rollback(config.get_device_id(), reservations.next(), index < restore_pins);
+        }
+    }
+
+    /// Applies the configs of several slots like [`PowerMgmt::apply`], a failure rolls back all of them.
+    ///
+    /// A power-mgmt request carries the budget of one slot and replaces the budget of that slot,
+    /// so a combined budget could not be attributed to the slots or released per slot later.
+    /// Instead every slot gets its own reservation. They are all made and finished within one
+    /// negotiation, so no other request reserves in between, and only finished once every
+    /// module accepted its config. The first rejected reservation fails the whole request.
+    fn apply_bulk(&mut self, mut configs: Vec<PowerConfig>) -> Result<Vec<u8>, Error> {
+        configs.sort_by_key(|config| config.get_device_id());
+
+        let mut changed = Vec::new();
+        for config in configs {
+            match self.is_update_necessary(&config) {
+                Ok(true) => changed.push(config),
+                Ok(false) => debug!("Slot {}: power config already active", config.get_device_id()),
+                Err(err) => return Err(err),
+            }
+        }
+        let mut configs = changed;
+        if configs.is_empty() {
+            return Ok(PowerMgmt::encode_shortfall((0, 0, 0)));
+        }
+
+        for (index, config) in configs.iter().enumerate() {
+            match self.suspend_device(config.get_device_id()) {
+                Ok(_) => (),
+                Err(err) => {
+                    self.rollback_bulk(&configs[..=index], Vec::new(), 0);
+                    return Err(err);
+                }
+            }
+        }
+        thread::sleep(settings::current().timeouts.suspend_settle()); // Implicit update_descriptor is async
+
+        let mut budgets = Vec::new();
+        for index in 0..configs.len() {
+            let result = self.update_config(&mut configs[index], true)
+                .and_then(|_| self.test_power_config(&configs[index]))
+                .and_then(|_| configs[index].budget().and_then(|budget| budget.to_wire()).map_err(Error::from));
+            match result {
+                Ok(value) => budgets.push(value),
+                Err(err) => {
+                    self.rollback_bulk(&configs, Vec::new(), 0);
+                    return Err(err);
+                }
+            }
+        }
+
+        let state = self.state.clone();
+        let _negotiation = state.negotiation.lock().expect("Could not lock negotiation"); // Until finished or rolled back
+        let mut client = state.power_mgmt.lock().expect("Could not lock power-mgmt client");
+        let mut connections: Vec<Box<dyn Connection>> = Vec::new();
+        for (index, (power_3v3, power_5v0, power_12v)) in budgets.into_iter().enumerate() {
+            let slot = configs[index].get_device_id();
+            debug!("Slot {}: 3v3: {:?} 5v0: {:?} 12v: {:?}",slot,power_3v3,power_5v0,power_12v);
+            match client.reserve(slot, (power_3v3, power_5v0, power_12v)) {
+                Ok((con_pm, None)) => connections.push(con_pm),
+                Ok((_, Some(shortfall))) => {
+                    drop(client);
+                    self.rollback_bulk(&configs, connections, 0);
+                    return Err(PowerMgmtError::BudgetExceeded { slot, shortfall }.into());
+                }
+                Err(err) => {
+                    drop(client);
+                    self.rollback_bulk(&configs, connections, 0);
+                    return Err(err);
+                }
+            }
+        }
+        drop(client);
+
+        for index in 0..configs.len() {
+            match self.set_power_config(&configs[index]) {
+                Ok(_) => (),
+                Err(err) => {
+                    self.rollback_bulk(&configs, connections, index + 1);
+                    return Err(err);
+                }
+            }
+        }
+
+        let mut descriptors = Vec::new();
+        for index in 0..configs.len() {
+            match self.update_descriptor(configs[index].get_device_id()) {
+                Ok(value) => descriptors.push(value),
+                Err(err) => {
+                    self.rollback_bulk(&configs, connections, configs.len());
+                    return Err(err);
+                }
+            }
+        }
+
+        // A failure rolls back already finished slots as well, their previous budget is registered again
+        let mut client = state.power_mgmt.lock().expect("Could not lock power-mgmt client");
+        for index in 0..connections.len() {
+            let slot = configs[index].get_device_id();
+            let err = match connections[index].finish_request() {
+                Ok(true) => continue,
+                Ok(false) => PowerMgmtError::PowerMgmtUnreachable { reason: format!("Finishing power config of slot {} failed", slot) },
+                Err(err) => {
+                    client.failed();
+                    PowerMgmtError::PowerMgmtUnreachable { reason: format!("Finishing power config of slot {} failed: {}", slot, err) }
+                }
+            };
+            for finished in &configs[..index] {
+                self.restore_committed(&mut client, finished.get_device_id());
+            }
+            drop(client);
+            self.rollback_bulk(&configs, connections, configs.len());
+            return Err(err.into());
+        }
+
+        if let Some(con_pm) = connections.pop() {
+            client.release(con_pm);
+        }
+        drop(client);
+        for (config, descriptor) in configs.into_iter().zip(descriptors.iter()) {
+            self.commit(config, descriptor);
+        }
+        return Ok(PowerMgmt::encode_shortfall((0, 0, 0)));
+    }
+
+    /// Replays the persisted power config of the module with `uid` unless it is already active.
+    fn restore(&mut self, slot: u8, uid: String) {
+        let slot_lock = self.state.slot_lock(slot);
+        let _slot = slot_lock.lock().expect("Could not lock slot");
+
+        let stored = match self.state.store.lock().expect("Could not lock store").get(&uid) {
+            Some(value) => value.clone(),
+            None => return,
+        };
+        match self.state.committed.lock().expect("Could not lock committed configs").get(&slot) {
+            Some(active) if active.uid == uid && active.config.to_payload() == stored => return,
+            _ => (),
+        }
+        let config = match PowerConfig::from_payload(slot, &stored) {
+            Ok(value) => value,
+            Err(err) => {
+                error!("Slot {}: persisted power config is invalid: {}", slot, err);
+                return;
+            }
+        };
+
+        info!("Slot {}: restoring power config of module {}", slot, uid);
+        let audit = vec![AuditSlot::new(self.state.committed_config(slot).as_ref(), &config)];
+        let response = self.apply(config).map(PowerMgmt::encode_shortfall);
+        self.audit(None, "restore", audit, &response);
+        match response {
+            Ok(shortfall) if shortfall.iter().all(|byte| *byte == 0) => (),
+            Ok(_) => error!("Slot {}: restoring power config failed, power budget exceeded", slot),
+            Err(err) => error!("Slot {}: restoring power config failed: {}", slot, err),
+        }
+    }
+
+    fn audit(&self, client: Option<u16>, command: &'static str, slots: Vec<AuditSlot>, response: &Result<Vec<u8>, Error>) {
+        let record = AuditRecord::new(client, command, slots, response.as_ref().map(|value| value.as_slice()));
+        self.state.audit.lock().expect("Could not lock audit log").append(&record);
+    }
+
     pub fn execute(&mut self, msg: &PMsg) -> PMsg {
+        let request = msg.get_msg();
+        let versioned = request.as_ref().and_then(|frame| RequestFrame::versioned_header(frame));
+        let result = self.power_management(msg.get_src(), request);
+        PowerMgmt::respond(msg.get_dst(), msg.get_src(), versioned, result)
+    }
+
+    /// Builds the response of the virtual device `vdev_id` to a request of `client`.
+    ///
+    /// `versioned` is the version and command of a versioned request, only those get the error code in `Response`.
+    fn respond(vdev_id: u16, client: u16, versioned: Option<(u8, u8)>, result: Result<Vec<u8>, Error>) -> PMsg {
         let mut tlv = TlvValue::new();
         tlv[Tag::DeviceTunnel] = TlvValue::new_array();
 
-        match self.power_management(msg) {
-            Ok(result) => {
+        match result {
+            Ok(response_ok) => {
                 tlv[Tag::DeviceTunnel] = TlvValue::new_array();
-                let mut response_ok: Vec<u8> = Vec::new();
-                response_ok.extend(result.0.to_be_bytes());
-                response_ok.extend(result.1.to_be_bytes());
-                response_ok.extend(result.2.to_be_bytes());
                 tlv[Tag::DeviceTunnel][Tag::Response] = TlvValue::Bytes(response_ok);
             }
             Err(err) => {
                 error!("{}",err);
+                let err = PowerMgmtError::from_io(&err);
                 tlv[Tag::DeviceTunnel][Tag::ErrorValue] = TlvValue::U16(ApiError::VirtualDeviceError as u16);
                 tlv[Tag::DeviceTunnel][Tag::ErrorMsg] = TlvValue::String(format!("{}", err));
+                if let Some(response) = PowerMgmt::error_response(versioned, &err) {
+                    tlv[Tag::DeviceTunnel][Tag::Response] = TlvValue::Bytes(response);
+                }
             }
         };
 
-        PMsg::create(msg.get_dst(), msg.get_src(), Ok(tlv.into_bytes()))
+        PMsg::create(vdev_id, client, Ok(tlv.into_bytes()))
     }
 
-    pub fn handle_function(vdev_id: u16, ctl_pair: ChannelPair<ManagedThreadState>, dev_pair: ChannelPair<PMsg>, mut shared: SharedStats) {
+    /// `Response` of a failed request, legacy clients expect none.
+    fn error_response(versioned: Option<(u8, u8)>, err: &PowerMgmtError) -> Option<Vec<u8>> {
+        versioned.map(|(version, command)| RequestFrame::encode_response(Some(version), command, err.to_wire()))
+    }
+
+    pub fn handle_function(vdev_id: u16, ctl_pair: ChannelPair<ManagedThreadState>, dev_pair: ChannelPair<PMsg>, shared: SharedStats) {
         let mut stopped = false;
 
         debug!("Started {} ", std::thread::current().name().expect("Could not get thread name"));
 
-        let mut mgmt = PowerMgmt::new(vdev_id, &dev_pair, &mut shared);
-        let lock_single_request = Mutex::new(true);
+        let heartbeat = watchdog::register("PowerMgmt");
+        let replies = Arc::new(Replies::new(vdev_id));
+        let modules = Arc::new(SdbpModules::new(vdev_id, dev_pair.tx().clone(), shared, replies.clone()));
+        let state = Arc::new(PowerMgmtState::new(modules));
+        let pool = WorkerPool::start(state.clone(), &settings::current().workers);
+        let mut router = Router { tx: dev_pair.tx().clone(), state: state.clone(), replies, pool, present: HashMap::new() };
 
         while !stopped {
+            heartbeat.beat();
             ManagedThreadUtil::is_stopped(&mut stopped, &ctl_pair);
-            let lock = lock_single_request.lock().expect("Could not lock mutex");
-            let res = match dev_pair.rx().recv_timeout(Duration::from_millis(150)) {
-                Ok(value) => {
-                    mgmt.execute(&value)
-                }
+            router.restore_reconnected();
+            router.maintain_power_mgmt();
+            match dev_pair.rx().recv_timeout(Duration::from_millis(150)) {
+                Ok(value) => router.route(value),
                 Err(_err) => continue,
             };
+        }
 
-            match dev_pair.tx().send(res) {
-                Err(_) => error!("Error while sending response for to client"),
-                _ => (),
+        // The UDS server is stopped first, so this only finishes running and already queued requests
+        while state.in_flight.load(Ordering::SeqCst) > 0 || !dev_pair.rx().is_empty() {
+            heartbeat.beat();
+            match dev_pair.rx().recv_timeout(Duration::from_millis(50)) {
+                Ok(value) => router.route(value),
+                Err(_err) => (),
             }
+        }
+        STOPPED.store(true, Ordering::SeqCst);
+        info!("Stopped {}", std::thread::current().name().expect("Could not get thread name"));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::powermgmt::data::{CMD_LEDGER, CMD_QUERY, HEADER_CLASS, HEADER_GROUP, VOLTAGE_12V, VOLTAGE_5V};
+    use crate::powermgmt::testing::{config, set_frame, Answer, Bus, Harness, PowerMgmtStep, Step, CLIENT};
+
+    /// 3V3, 5V and 12V capacity of power-mgmt in the tests
+    const CAPACITY: (u32, u32, u32) = (10_000, 10_000, 10_000);
+
+    fn code(err: &Error) -> u16 {
+        PowerMgmtError::from_io(err).code()
+    }
+
+    #[test]
+    fn applies_config_and_reserves_budget() {
+        let harness = Harness::new(CAPACITY);
+        harness.plug(1, "io-1", (100, 500, 0));
+
+        let response = harness.request(set_frame(1, &[(VOLTAGE_5V, 100), (VOLTAGE_12V, 50)])).unwrap();
+
+        assert_eq!(response, vec![0; 6]);
+        // 5V: idle + 100 mA * 5 V + 200 mW overhead + 400 mW overhead of the 12V pin, 12V: 50 mA * 12 V
+        assert_eq!(harness.power_mgmt.budget(1), Some((100, 1600, 600)));
+        let module = harness.modules.module(1);
+        assert_eq!(module.pins, vec![(VOLTAGE_5V, 100), (VOLTAGE_12V, 50)]);
+        assert!(!module.suspended);
+        assert_eq!(harness.modules.calls(1), vec![Step::Suspend, Step::Test, Step::Set, Step::Descriptor]);
+    }
+
+    #[test]
+    fn slot_is_connected_only_if_sysfs_knows_it() {
+        let harness = Harness::new(CAPACITY);
+        harness.modules.insert(2, "io-2", (0, 0, 0)); // Enumerated, but no sysfs entry
+
+        let err = harness.request(set_frame(2, &[(VOLTAGE_5V, 100)])).unwrap_err();
+        assert_eq!(PowerMgmtError::from_io(&err), PowerMgmtError::SlotNotConnected { slot: 2 });
+        assert!(harness.modules.calls(2).is_empty());
+
+        harness.plug(3, "io-3", (0, 0, 0));
+        harness.unplug(3);
+        let err = harness.request(set_frame(3, &[(VOLTAGE_5V, 100)])).unwrap_err();
+        assert_eq!(code(&err), 1);
+    }
+
+    #[test]
+    fn reports_shortfall_and_resumes_module() {
+        let harness = Harness::new((10_000, 10_000, 500));
+        harness.plug(1, "io-1", (0, 0, 0));
+
+        let response = harness.request(set_frame(1, &[(VOLTAGE_12V, 100)])).unwrap();
+
+        assert_eq!(response, vec![0, 0, 0, 0, 0x02, 0xBC]); // 1200 mW requested on 12V, 700 mW missing
+        assert_eq!(harness.power_mgmt.budget(1), None);
+        assert!(harness.modules.module(1).pins.is_empty());
+        assert_eq!(harness.modules.calls(1).last(), Some(&Step::Descriptor));
+    }
+
+    #[test]
+    fn config_rejected_by_firmware_is_reported() {
+        let harness = Harness::new(CAPACITY);
+        harness.plug(1, "io-1", (0, 0, 0));
+        harness.modules.reject(1, Step::Test, 0x21);
+
+        let err = harness.request(set_frame(1, &[(VOLTAGE_5V, 100)])).unwrap_err();
+
+        assert_eq!(PowerMgmtError::from_io(&err), PowerMgmtError::ConfigRejected { slot: 1, status: 0x21 });
+        assert_eq!(harness.power_mgmt.requests(), 0);
+    }
+
+    #[test]
+    fn module_timeout_is_reported_and_audited() {
+        let harness = Harness::new(CAPACITY);
+        harness.plug(1, "io-1", (0, 0, 0));
+        for _ in 0..3 {
+            harness.modules.fail(1, Step::Suspend, PowerMgmtError::Timeout { slot: 1 });
+        }
+
+        let err = harness.request(set_frame(1, &[(VOLTAGE_5V, 100)])).unwrap_err();
+
+        assert_eq!(code(&err), 6);
+        assert_eq!(harness.power_mgmt.requests(), 0);
+        assert_eq!(harness.audit_log().lines().count(), 1);
+    }
+
+    #[test]
+    fn unreachable_power_mgmt_is_reported() {
+        let harness = Harness::new(CAPACITY);
+        harness.plug(1, "io-1", (0, 0, 0));
+        for _ in 0..3 {
+            harness.power_mgmt.fail(PowerMgmtStep::Connect);
+        }
+
+        let err = harness.request(set_frame(1, &[(VOLTAGE_5V, 100)])).unwrap_err();
+
+        assert_eq!(code(&err), 5);
+        assert_eq!(harness.power_mgmt.budget(1), None);
+        assert!(harness.modules.module(1).pins.is_empty());
+    }
+
+    #[test]
+    fn health_request_reports_power_mgmt_health() {
+        let harness = Harness::new(CAPACITY);
+        harness.plug(1, "io-1", (0, 0, 0));
+        let health = vec![0, HEADER_CLASS, HEADER_GROUP, data::CMD_HEALTH];
+        assert_eq!(harness.request(health.clone()).unwrap(), vec![0, 0, 0, 0, 0]);
+
+        for _ in 0..3 {
+            harness.power_mgmt.fail(PowerMgmtStep::Connect);
+        }
+        harness.request(set_frame(1, &[(VOLTAGE_5V, 100)])).unwrap_err();
+        assert_eq!(harness.request(health.clone()).unwrap(), vec![2, 0, 0, 0, 3]);
+
+        harness.request(set_frame(1, &[(VOLTAGE_5V, 100)])).unwrap();
+        assert_eq!(harness.request(health).unwrap(), vec![1, 0, 0, 0, 0]);
+    }
+
+    /// Pins slot 1 runs before a failing request
+    const PREVIOUS: [(u8, u16); 1] = [(VOLTAGE_5V, 100)];
+    /// Budget of [`PREVIOUS`] including the idle power
+    const PREVIOUS_BUDGET: (u16, u16, u16) = (100, 1200, 0);
+    const NEXT: [(u8, u16); 1] = [(VOLTAGE_12V, 100)];
+
+    /// Applies [`PREVIOUS`] to slot 1, then requests [`NEXT`] after `fault` scripted its failure.
+    fn fail_next<F: FnOnce(&Harness)>(capacity: (u32, u32, u32), fault: F) -> (Harness, Result<Vec<u8>, Error>) {
+        let harness = Harness::new(capacity);
+        harness.plug(1, "io-1", (100, 500, 0));
+        harness.request(set_frame(1, &PREVIOUS)).unwrap();
+        fault(&harness);
+        let result = harness.request(set_frame(1, &NEXT));
+        (harness, result)
+    }
+
+    /// The reservation of the failed request is dropped, the previous pins and budget are active and the module runs.
+    fn assert_rolled_back(harness: &Harness) {
+        assert_eq!(harness.power_mgmt.reservations(), 0);
+        assert_eq!(harness.power_mgmt.budget(1), Some(PREVIOUS_BUDGET));
+        let module = harness.modules.module(1);
+        assert_eq!(module.pins, PREVIOUS.to_vec());
+        assert!(!module.suspended);
+        assert_eq!(harness.modules.calls(1).last(), Some(&Step::Descriptor));
+        assert_eq!(harness.state.committed_config(1).map(|config| config.pin_vec()), Some(PREVIOUS.to_vec()));
+    }
+
+    #[test]
+    fn rollback_after_failed_suspend() {
+        let (harness, result) = fail_next(CAPACITY, |harness| harness.modules.fail(1, Step::Suspend, PowerMgmtError::Internal { reason: "bus error".to_string() }));
+        assert_eq!(code(&result.unwrap_err()), 0xFF);
+        assert_rolled_back(&harness);
+    }
+
+    #[test]
+    fn rollback_after_failed_update_config() {
+        // The second descriptor lookup of the request reads the idle power
+        let (harness, result) = fail_next(CAPACITY, |harness| harness.modules.fail_later(1, Step::Info, 1, PowerMgmtError::Internal { reason: "not enumerated".to_string() }));
+        assert_eq!(code(&result.unwrap_err()), 0xFF);
+        assert_rolled_back(&harness);
+    }
 
-            if *lock == true { // Ensure the lock is held
-                trace!("Lock held");
+    #[test]
+    fn rollback_after_failed_test() {
+        let (harness, result) = fail_next(CAPACITY, |harness| harness.modules.reject(1, Step::Test, 3));
+        assert_eq!(code(&result.unwrap_err()), 3);
+        assert_rolled_back(&harness);
+    }
+
+    #[test]
+    fn rollback_after_failed_reserve() {
+        let (harness, result) = fail_next(CAPACITY, |harness| {
+            for _ in 0..3 {
+                harness.power_mgmt.fail(PowerMgmtStep::Request);
             }
+        });
+        assert_eq!(code(&result.unwrap_err()), 5);
+        assert_rolled_back(&harness);
+    }
+
+    #[test]
+    fn rollback_after_rejected_budget() {
+        let (harness, result) = fail_next((10_000, 10_000, 1000), |_| ());
+        assert_eq!(result.unwrap(), vec![0, 0, 0, 0, 0, 200]);
+        assert_rolled_back(&harness);
+    }
+
+    #[test]
+    fn rollback_after_failed_set() {
+        let (harness, result) = fail_next(CAPACITY, |harness| harness.modules.fail(1, Step::Set, PowerMgmtError::Internal { reason: "bus error".to_string() }));
+        assert_eq!(code(&result.unwrap_err()), 0xFF);
+        assert_rolled_back(&harness);
+    }
+
+    #[test]
+    fn rollback_after_failed_update_descriptor() {
+        let (harness, result) = fail_next(CAPACITY, |harness| harness.modules.fail(1, Step::Descriptor, PowerMgmtError::Internal { reason: "bus error".to_string() }));
+        assert_eq!(code(&result.unwrap_err()), 0xFF);
+        assert_rolled_back(&harness);
+    }
+
+    #[test]
+    fn rollback_after_failed_finish() {
+        let (harness, result) = fail_next(CAPACITY, |harness| harness.power_mgmt.fail(PowerMgmtStep::Finish));
+        assert_eq!(code(&result.unwrap_err()), 5);
+        assert_rolled_back(&harness);
+
+        let (harness, result) = fail_next(CAPACITY, |harness| harness.power_mgmt.fail(PowerMgmtStep::Refuse));
+        assert_eq!(code(&result.unwrap_err()), 5);
+        assert_rolled_back(&harness);
+    }
+
+    #[test]
+    fn rollback_without_previous_config_restores_module_defaults() {
+        let harness = Harness::new(CAPACITY);
+        harness.plug(1, "io-1", (0, 0, 0));
+        harness.modules.fail(1, Step::Descriptor, PowerMgmtError::Internal { reason: "bus error".to_string() });
+
+        harness.request(set_frame(1, &NEXT)).unwrap_err();
+
+        assert_eq!(harness.power_mgmt.reservations(), 0);
+        assert_eq!(harness.power_mgmt.budget(1), None);
+        assert!(harness.modules.module(1).pins.is_empty());
+        assert_eq!(harness.modules.calls(1), vec![Step::Suspend, Step::Test, Step::Set, Step::Descriptor, Step::Set, Step::Descriptor]);
+        assert_eq!(harness.state.committed_config(1).map(|config| config.pin_vec()), None);
+    }
+
+    #[test]
+    fn bulk_rollback_releases_finished_budgets() {
+        let harness = Harness::new(CAPACITY);
+        harness.plug(1, "io-1", (0, 0, 0));
+        harness.plug(2, "io-2", (0, 0, 0));
+        harness.request(set_frame(2, &PREVIOUS)).unwrap();
+        let mut frame = vec![0, HEADER_CLASS, HEADER_GROUP, data::CMD_BULK];
+        frame.extend([1, 1, VOLTAGE_5V, 0, 10]);
+        frame.extend([2, 1, VOLTAGE_12V, 0, 10]);
+        // Slot 1 is finished first, then finishing slot 2 fails
+        harness.power_mgmt.fail_later(PowerMgmtStep::Finish, 1);
+
+        let err = harness.request(frame).unwrap_err();
+
+        assert_eq!(code(&err), 5);
+        assert_eq!(harness.power_mgmt.reservations(), 0);
+        assert_eq!(harness.power_mgmt.budget(1), Some((0, 0, 0)));
+        assert_eq!(harness.power_mgmt.budget(2), Some((0, 700, 0)));
+        assert!(harness.modules.module(1).pins.is_empty());
+        assert_eq!(harness.modules.module(2).pins, PREVIOUS.to_vec());
+    }
+
+    #[test]
+    fn validate_leaves_committed_budget_alone() {
+        let harness = Harness::new(CAPACITY);
+        harness.plug(1, "io-1", (100, 500, 0));
+        harness.request(set_frame(1, &PREVIOUS)).unwrap();
+        let validate = |pins: &[(u8, u16)]| {
+            let mut frame = set_frame(1, pins);
+            frame[3] = data::CMD_VALIDATE;
+            harness.request(frame)
+        };
+        let calls = harness.modules.calls(1).len();
+        let requests = harness.power_mgmt.requests();
+
+        assert_eq!(validate(&PREVIOUS).unwrap(), vec![0; 6]);
+        assert_eq!(validate(&[(VOLTAGE_12V, 1000)]).unwrap(), vec![0, 0, 0, 0, 0x07, 0xD0]);
+        harness.power_mgmt.fail(PowerMgmtStep::Request);
+        harness.power_mgmt.fail(PowerMgmtStep::Request);
+        harness.power_mgmt.fail(PowerMgmtStep::Request);
+        assert_eq!(code(&validate(&PREVIOUS).unwrap_err()), 5);
+
+        assert_eq!(harness.power_mgmt.requests(), requests + 2);
+        assert_eq!(harness.power_mgmt.reservations(), 0);
+        assert_eq!(harness.power_mgmt.budget(1), Some(PREVIOUS_BUDGET));
+        assert_eq!(harness.state.committed_config(1).map(|config| config.pin_vec()), Some(PREVIOUS.to_vec()));
+        let module = harness.modules.module(1);
+        assert_eq!(module.pins, PREVIOUS.to_vec());
+        assert!(!module.suspended);
+        assert_eq!(harness.modules.calls(1)[calls..], [Step::Test, Step::Test, Step::Test]);
+    }
+
+    /// Applies two configs in turn to a module idling with `idle` and returns the budget of the second.
+    fn budget_after_reconfig(idle: (u16, u16, u16)) -> (u16, u16, u16) {
+        let harness = Harness::new(CAPACITY);
+        harness.plug(1, "io-1", idle);
+        harness.request(set_frame(1, &[(VOLTAGE_5V, 100), (VOLTAGE_12V, 100)])).unwrap();
+        harness.request(set_frame(1, &[(VOLTAGE_5V, 50), (VOLTAGE_12V, 10)])).unwrap();
+        harness.power_mgmt.budget(1).unwrap()
+    }
+
+    /// Load of the second config of [`budget_after_reconfig`]
+    fn reconfig_load() -> (u16, u16, u16) {
+        config(1, &[(VOLTAGE_5V, 50), (VOLTAGE_12V, 10)]).pin_load().unwrap().to_wire().unwrap()
+    }
+
+    #[test]
+    fn idle_power_3v3_is_read_while_suspended() {
+        assert_eq!(budget_after_reconfig((300, 0, 0)).0, 300 + reconfig_load().0);
+    }
+
+    #[test]
+    fn idle_power_5v_is_read_while_suspended() {
+        assert_eq!(budget_after_reconfig((0, 500, 0)).1, 500 + reconfig_load().1);
+    }
+
+    #[test]
+    fn idle_power_12v_is_read_while_suspended() {
+        assert_eq!(budget_after_reconfig((0, 0, 700)).2, 700 + reconfig_load().2);
+    }
+
+    #[test]
+    fn validate_uses_idle_power_of_last_suspend() {
+        let harness = Harness::new((10_000, 1200, 10_000));
+        harness.plug(1, "io-1", (100, 500, 0));
+        let mut frame = set_frame(1, &PREVIOUS);
+        frame[3] = data::CMD_VALIDATE;
+        harness.request(set_frame(1, &PREVIOUS)).unwrap();
+        // Driver restarted: without a baseline the running descriptor includes the load of the active pins
+        harness.state.idle.lock().unwrap().clear();
+        assert_eq!(harness.request(frame.clone()).unwrap(), vec![0, 0, 0x02, 0xBC, 0, 0]);
+
+        harness.request(set_frame(1, &NEXT)).unwrap();
+        assert_eq!(harness.request(frame).unwrap(), vec![0; 6]);
+    }
+
+    #[test]
+    fn router_answers_watchdog_probe() {
+        let harness = Harness::new(CAPACITY);
+        let (tx, rx) = crossbeam_channel::unbounded();
+        let pool = WorkerPool::start(harness.state.clone(), &settings::Workers { threads: 1, queue: 1 });
+        let mut router = Router { tx, state: harness.state.clone(), replies: Arc::new(Replies::new(VDEV_ID)), pool, present: HashMap::new() };
+
+        router.route(PMsg::create(watchdog::PROBE_VDEV_ID, VDEV_ID, Ok(watchdog::PROBE.to_vec())));
+
+        let answer = rx.try_recv().unwrap();
+        assert_eq!((answer.get_src(), answer.get_dst()), (VDEV_ID, watchdog::PROBE_VDEV_ID));
+        assert_eq!(answer.get_msg(), Some(watchdog::PROBE.to_vec()));
+        assert_eq!(harness.state.in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn module_replies_are_routed_to_the_waiting_command() {
+        let bus = Bus::new(CAPACITY, &settings::Workers { threads: 1, queue: 1 });
+        bus.plug(1, "io-1");
+
+        bus.modules.suspend(1).unwrap();
 
+        assert_eq!(bus.frames(1), vec![SdbpModules::suspend_command()]);
+    }
+
+    #[test]
+    fn lost_module_command_times_out() {
+        let bus = Bus::new(CAPACITY, &settings::Workers { threads: 1, queue: 1 });
+        bus.plug(1, "io-1");
+        bus.script(1, vec![Answer::Lose]);
+
+        let err = bus.modules.suspend(1).unwrap_err();
+        assert_eq!(code(&err), 6);
+    }
+
+    #[test]
+    fn timed_out_module_command_is_retried_after_the_reply_timeout() {
+        let bus = Bus::new(CAPACITY, &settings::Workers { threads: 1, queue: 1 });
+        bus.plug(1, "io-1");
+        bus.script(1, vec![Answer::Lose]);
+
+        // The retry must not be taken for the lost command, its reply would be discarded as late
+        let policy = settings::current().retry.suspend.clone();
+        assert!(retry::run("suspend", 1, &policy, || bus.modules.suspend(1)).is_ok());
+        assert_eq!(bus.frames(1).len(), 2);
+    }
+
+    #[test]
+    fn client_requests_are_answered_through_the_router() {
+        let bus = Bus::new(CAPACITY, &settings::Workers { threads: 1, queue: 1 });
+        bus.send(PMsg::create(CLIENT, VDEV_ID, Ok(vec![0, HEADER_CLASS, HEADER_GROUP, data::CMD_CAPABILITIES])));
+
+        let response = bus.responses.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!((response.get_src(), response.get_dst()), (VDEV_ID, CLIENT));
+    }
+
+    #[test]
+    fn modules_and_clients_are_told_apart_by_their_id() {
+        let bus = Bus::new(CAPACITY, &settings::Workers { threads: 1, queue: 1 });
+        bus.plug(1, "io-1");
+        let client = 0x1001; // Same low byte as slot 1
+
+        bus.send(PMsg::create(5, VDEV_ID, Ok(vec![0]))); // Late reply of a module that is gone
+        bus.send(PMsg::create(client, VDEV_ID, Ok(vec![0, HEADER_CLASS, HEADER_GROUP, data::CMD_CAPABILITIES])));
+
+        let response = bus.responses.recv_timeout(Duration::from_secs(1)).unwrap();
+        assert_eq!((response.get_src(), response.get_dst()), (VDEV_ID, client));
+        thread::sleep(Duration::from_millis(50));
+        assert!(bus.frames(5).is_empty());
+        assert!(bus.responses.try_recv().is_err());
+    }
+
+    #[test]
+    fn concurrent_requests_do_not_overcommit() {
+        let harness = Harness::new((10_000, 1_000, 10_000));
+        harness.plug(1, "io-1", (0, 0, 0));
+        harness.plug(2, "io-2", (0, 0, 0));
+        harness.modules.delay(1, Step::Set, Duration::from_millis(200));
+
+        let mut worker = harness.worker();
+        let first = thread::spawn(move || worker.power_management(CLIENT, Some(set_frame(1, &[(VOLTAGE_5V, 150)]))));
+        while harness.power_mgmt.reservations() == 0 {
+            thread::sleep(Duration::from_millis(1));
         }
-        info!("Stopped {}", std::thread::current().name().expect("Could not get thread name"));
+        // Slot 1 holds its reservation while the module takes over the pins
+        let second = harness.request(set_frame(2, &[(VOLTAGE_5V, 150)])).unwrap();
+
+        assert_eq!(first.join().unwrap().unwrap(), vec![0; 6]);
+        assert_ne!(second, vec![0; 6]); // Exceeds the budget left by slot 1
+        assert_eq!(harness.power_mgmt.budget(2), None);
+        assert!(!harness.power_mgmt.overcommitted());
+    }
+
+    #[test]
+    fn only_versioned_errors_have_a_response() {
+        let err = PowerMgmtError::SlotNotConnected { slot: 3 };
+        assert_eq!(PowerMgmt::error_response(None, &err), None);
+        assert_eq!(PowerMgmt::error_response(Some((1, data::CMD_SET)), &err), Some(vec![1, data::CMD_SET, 0, 1]));
+
+        // Versioned frames are recognized by their header, also if the rest is malformed
+        let frame = vec![3, HEADER_CLASS, HEADER_GROUP, data::CMD_VERSIONED, 9, data::CMD_SET, 0xFF];
+        assert_eq!(RequestFrame::versioned_header(&frame), Some((9, data::CMD_SET)));
+        assert_eq!(RequestFrame::versioned_header(&set_frame(3, &PREVIOUS)), None);
+    }
+
+    #[test]
+    fn bulk_shortfall_is_reported_as_exceeded_budget() {
+        let harness = Harness::new((10_000, 10_000, 100));
+        harness.plug(1, "io-1", (0, 0, 0));
+        harness.plug(2, "io-2", (0, 0, 0));
+        let mut frame = vec![0, HEADER_CLASS, HEADER_GROUP, data::CMD_BULK];
+        frame.extend([1, 1, VOLTAGE_5V, 0, 10]);
+        frame.extend([2, 1, VOLTAGE_12V, 0, 10]);
+
+        let result = harness.request(frame);
+
+        let record = AuditRecord::new(Some(CLIENT), "bulk", Vec::new(), result.as_deref());
+        assert_eq!((record.result, record.shortfall, record.error_code), ("budget_exceeded", Some([0, 0, 20]), None));
+        let err = PowerMgmtError::from_io(&result.unwrap_err());
+        assert_eq!(err, PowerMgmtError::BudgetExceeded { slot: 2, shortfall: (0, 0, 20) });
+        assert_eq!(harness.power_mgmt.reservations(), 0);
+        assert_eq!(harness.power_mgmt.budget(1), None);
+        assert!(harness.modules.module(1).pins.is_empty());
     }
-}
\ No newline at end of file
+
+    #[test]
+    fn query_returns_applied_config() {
+        let harness = Harness::new(CAPACITY);
+        harness.plug(1, "io-1", (100, 0, 0));
+        let err = harness.request(vec![1, HEADER_CLASS, HEADER_GROUP, CMD_QUERY]).unwrap_err();
+        assert_eq!(code(&err), 7);
+
+        harness.request(set_frame(1, &[(VOLTAGE_5V, 10)])).unwrap();
+        let response = harness.request(vec![1, HEADER_CLASS, HEADER_GROUP, CMD_QUERY]).unwrap();
+
+        let mut expected = vec![0, 100, 0, 250, 0, 0];
+        expected.extend(config(1, &[(VOLTAGE_5V, 10)]).to_payload());
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn ledger_lists_slots_in_order_with_totals() {
+        let harness = Harness::new(CAPACITY);
+        harness.plug(1, "io-1", (100, 0, 0));
+        harness.plug(2, "io-2", (0, 0, 30));
+        assert_eq!(harness.request(vec![0, HEADER_CLASS, HEADER_GROUP, CMD_LEDGER]).unwrap(), vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+
+        harness.request(set_frame(2, &[(VOLTAGE_12V, 10)])).unwrap();
+        harness.request(set_frame(1, &[(VOLTAGE_5V, 10)])).unwrap();
+        let response = harness.request(vec![0, HEADER_CLASS, HEADER_GROUP, CMD_LEDGER]).unwrap();
+
+        let mut expected = vec![2];
+        expected.extend([1, 0, 0, 0, 100, 0, 0, 0, 250, 0, 0, 0, 0]); // 5V: 10 mA plus pin overhead
+        expected.extend([2, 0, 0, 0, 0, 0, 0, 1, 144, 0, 0, 0, 150]); // 5V: pin overhead, 12V: 30 idle plus 10 mA
+        expected.extend([0, 0, 0, 100, 0, 0, 2, 138, 0, 0, 0, 150]); // Totals
+        assert_eq!(response, expected);
+    }
+
+    #[test]
+    fn unchanged_config_is_not_applied_again() {
+        let harness = Harness::new(CAPACITY);
+        harness.plug(1, "io-1", (0, 0, 0));
+        harness.request(set_frame(1, &[(VOLTAGE_5V, 10)])).unwrap();
+
+        harness.request(set_frame(1, &[(VOLTAGE_5V, 10)])).unwrap();
+
+        assert_eq!(harness.power_mgmt.requests(), 1);
+        assert_eq!(harness.modules.calls(1).len(), 4);
+    }
+
+    #[test]
+    fn bulk_applies_every_slot() {
+        let harness = Harness::new(CAPACITY);
+        harness.plug(1, "io-1", (0, 0, 0));
+        harness.plug(2, "io-2", (0, 0, 0));
+        let mut frame = vec![0, HEADER_CLASS, HEADER_GROUP, data::CMD_BULK];
+        frame.extend([1, 1, VOLTAGE_5V, 0, 10]);
+        frame.extend([2, 1, VOLTAGE_12V, 0, 10]);
+
+        let response = harness.request(frame).unwrap();
+
+        assert_eq!(&response[..6], &[0; 6]);
+        assert_eq!(harness.power_mgmt.budget(1), Some((0, 250, 0)));
+        assert_eq!(harness.power_mgmt.budget(2), Some((0, 400, 120)));
+        assert_eq!(harness.modules.module(2).pins, vec![(VOLTAGE_12V, 10)]);
+    }
+
+    #[test]
+    fn restores_persisted_config_of_reconnected_module() {
+        let harness = Harness::new(CAPACITY);
+        harness.plug(1, "io-1", (0, 0, 0));
+        harness.request(set_frame(1, &[(VOLTAGE_5V, 10)])).unwrap();
+
+        // Module replugged: the driver forgets the slot, the module starts without pins
+        harness.state.committed.lock().unwrap().remove(&1);
+        harness.unplug(1);
+        harness.plug(1, "io-1", (0, 0, 0));
+        harness.worker().restore(1, "io-1".to_string());
+
+        assert_eq!(harness.modules.module(1).pins, vec![(VOLTAGE_5V, 10)]);
+        assert_eq!(harness.power_mgmt.requests(), 2);
+        assert_eq!(harness.state.committed_config(1).map(|config| config.to_payload()), Some(config(1, &[(VOLTAGE_5V, 10)]).to_payload()));
+    }
+
+    #[test]
+    fn reregisters_budgets_after_power_mgmt_restart() {
+        let harness = Harness::new(CAPACITY);
+        harness.plug(1, "io-1", (0, 0, 0));
+        harness.request(set_frame(1, &[(VOLTAGE_5V, 10)])).unwrap();
+
+        harness.power_mgmt.restart();
+        assert_eq!(harness.state.power_mgmt.lock().unwrap().maintenance(), Some(Maintenance::Reregister));
+        harness.worker().maintain(Maintenance::Reregister);
+
+        assert_eq!(harness.power_mgmt.budget(1), Some((0, 250, 0)));
+        assert_eq!(harness.state.power_mgmt.lock().unwrap().maintenance(), None);
+    }
+}
@@ -0,0 +1,228 @@
+use std::collections::HashMap;
+use std::io::Error;
+use std::sync::{Arc, Mutex};
+
+use crossbeam_channel::Sender;
+use noreya_sdbp::*;
+use noreya_sdbp::datatypes::Descriptor;
+use noreya_sdbp::drv::core::*;
+use noreya_sdbp::sdbp::*;
+use noreya_sdbp::sdbp::response::SdbpResponse;
+use noreya_sdbp::util::*;
+use sdbp::request::custom::io::IoBuilder;
+use sdbp::response::custom::io::powermgmt::SetPowerConfig as SetPowerConfigResponse;
+use sdbp::response::custom::io::powermgmt::TestPowerConfig as TestPowerConfigResponse;
+
+use crate::powermgmt::data::MilliWatts;
+use crate::powermgmt::error::PowerMgmtError;
+use crate::powermgmt::helper;
+use crate::powermgmt::replies::Replies;
+use crate::settings;
+
+/// Descriptor values of a module the power config depends on.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModuleInfo {
+    pub uid: String,
+    pub max_power_3v3: MilliWatts,
+    pub max_power_5v0: MilliWatts,
+    pub max_power_12v: MilliWatts,
+}
+
+impl From<&Descriptor> for ModuleInfo {
+    fn from(device: &Descriptor) -> ModuleInfo {
+        ModuleInfo {
+            uid: device.uid().to_string(),
+            max_power_3v3: MilliWatts::from(device.max_power_3v3()),
+            max_power_5v0: MilliWatts::from(device.max_power_5v()),
+            max_power_12v: MilliWatts::from(device.max_power_12v()),
+        }
+    }
+}
+
+/// Commands the PowerMgmt device sends to the IO modules.
+///
+/// Whether a slot is connected is decided by the [`SlotResolver`](super::slots::SlotResolver) before.
+pub trait Modules: Send + Sync {
+    /// UID of every enumerated module by slot
+    fn present(&self) -> HashMap<u8, String>;
+
+    /// Descriptor of the module in a slot as it was enumerated last.
+    fn info(&self, slot: u8) -> Result<ModuleInfo, Error>;
+
+    fn suspend(&self, slot: u8) -> Result<(), Error>;
+
+    /// Makes the module update its descriptor, which also resumes it, and returns the updated descriptor.
+    fn update_descriptor(&self, slot: u8) -> Result<ModuleInfo, Error>;
+
+    /// Returns the firmware status, 0 if the module accepts the pins.
+    fn test_power_config(&self, slot: u8, pins: &[(u8, u16)]) -> Result<FirmwareStatus, Error>;
+
+    /// Returns the firmware status, 0 if the module took over the pins.
+    fn set_power_config(&self, slot: u8, pins: &[(u8, u16)]) -> Result<FirmwareStatus, Error>;
+}
+
+/// `status` of a TestPowerConfig or SetPowerConfig response.
+///
+/// Its meaning is defined by the module firmware. The status table is not published,
+/// so the driver passes the raw value on instead of decoding the pin and limit.
+pub type FirmwareStatus = u8;
+
+/// IO modules reached through the dispatcher, their replies are handed over by [`Replies`].
+pub struct SdbpModules {
+    vdev_id: u16,
+    tx: Sender<PMsg>,
+    shared: Mutex<SharedStats>,
+    replies: Arc<Replies>,
+}
+
+impl SdbpModules {
+    pub fn new(vdev_id: u16, tx: Sender<PMsg>, shared: SharedStats, replies: Arc<Replies>) -> SdbpModules {
+        SdbpModules { vdev_id, tx, shared: Mutex::new(shared), replies }
+    }
+
+    fn shared(&self) -> SharedStats {
+        self.shared.lock().expect("Could not lock shared stats").clone()
+    }
+
+    /// Sends a command to a module and waits for its reply.
+    fn transceive(&self, dev_id: u16, cmd: Vec<u8>) -> Result<PMsg, Error> {
+        let (reply_tx, reply_rx) = crossbeam_channel::bounded(1);
+        let seq = self.replies.expect(dev_id, reply_tx);
+
+        match self.tx.send(PMsg::create(self.vdev_id, dev_id, Ok(cmd))) {
+            Ok(_) => (),
+            Err(err) => {
+                error!("{}",err);
+                self.replies.cancel(dev_id, seq);
+                return Err(PowerMgmtError::Internal { reason: format!("Sending command to slot {} failed", dev_id) }.into());
+            }
+        }
+
+        match reply_rx.recv_timeout(settings::current().timeouts.device_reply()) {
+            Ok(value) => Ok(value),
+            Err(err) => {
+                if !self.replies.abandon(dev_id, seq) {
+                    if let Ok(value) = reply_rx.try_recv() {
+                        return Ok(value); // Delivered right after the timeout
+                    }
+                }
+                error!("{}",err);
+                Err(PowerMgmtError::Timeout { slot: dev_id as u8 }.into())
+            }
+        }
+    }
+
+    /// Frame of the command suspending a module.
+    pub fn suspend_command() -> Vec<u8> {
+        CoreBuilder::new().control().mode_suspend().expect("Could not build cmd")
+    }
+
+    fn reply_message(response: PMsg, slot: u8) -> Result<Vec<u8>, Error> {
+        match response.get_msg() {
+            None => Err(PowerMgmtError::Internal { reason: format!("Could not get message from slot {}", slot) }.into()),
+            Some(val) => Ok(val),
+        }
+    }
+}
+
+impl Modules for SdbpModules {
+    fn present(&self) -> HashMap<u8, String> {
+        let mut present = HashMap::new();
+        let mut stats = self.shared().read();
+        for device in stats.get_devices() {
+            present.insert(device.adr() as u8, device.uid().to_string());
+        }
+        present
+    }
+
+    fn info(&self, slot: u8) -> Result<ModuleInfo, Error> {
+        let helper = match helper::PowerMgmtHelper::new(slot as u16, &mut self.shared()) {
+            Ok(value) => value,
+            Err(err) => return Err(err),
+        };
+        Ok(ModuleInfo::from(helper.get_descriptor()))
+    }
+
+    fn suspend(&self, slot: u8) -> Result<(), Error> {
+        match self.transceive(slot as u16, SdbpModules::suspend_command()) {
+            Ok(_) => Ok(()),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn update_descriptor(&self, slot: u8) -> Result<ModuleInfo, Error> {
+        let mut shared = self.shared();
+        let mut helper = match helper::PowerMgmtHelper::new(slot as u16, &mut shared) {
+            Ok(value) => value,
+            Err(err) => return Err(err),
+        };
+
+        let request = sdbp::request::core::control::ControlBuilder::new().update_descriptor().expect("Could not build cmd");
+        match self.transceive(slot as u16, request) {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        }
+
+        match helper.wait_for_update_descriptor(&mut shared, settings::current().timeouts.descriptor_wait()) {
+            Ok(_) => {}
+            Err(_) => {
+                debug!("Descriptor did not change")
+            }
+        }
+        return Ok(ModuleInfo::from(helper.get_descriptor()));
+    }
+
+    fn test_power_config(&self, slot: u8, pins: &[(u8, u16)]) -> Result<FirmwareStatus, Error> {
+        debug!("Slot {}: test power config", slot);
+        let cmd_test_pwr_config = match IoBuilder::new().powermgmt().test_power_config(pins.to_vec()) {
+            Ok(value) => value,
+            Err(err) => {
+                return Err(err);
+            }
+        };
+
+        let response = match self.transceive(slot as u16, cmd_test_pwr_config) {
+            Ok(value) => value,
+            Err(err) => return Err(err),
+        };
+
+        let resp = match SdbpModules::reply_message(response, slot) {
+            Ok(value) => value,
+            Err(err) => return Err(err),
+        };
+
+        match TestPowerConfigResponse::from_raw(resp) {
+            Ok(value) => Ok(value.status),
+            Err(err) => Err(PowerMgmtError::Internal { reason: format!("Parsing from slot {} failed: {}", slot, err) }.into()),
+        }
+    }
+
+    fn set_power_config(&self, slot: u8, pins: &[(u8, u16)]) -> Result<FirmwareStatus, Error> {
+        trace!("Slot {}: set power config", slot);
+        let cmd_set_pwr_config = match IoBuilder::new().powermgmt().set_power_config(pins.to_vec()) {
+            Ok(value) => value,
+            Err(err) => {
+                error!("{}", err);
+                return Err(err);
+            }
+        };
+
+        let response = match self.transceive(slot as u16, cmd_set_pwr_config) {
+            Ok(value) => value,
+            Err(err) => return Err(err),
+        };
+
+        let msg = match SdbpModules::reply_message(response, slot) {
+            Ok(value) => value,
+            Err(err) => return Err(err),
+        };
+
+        match SetPowerConfigResponse::from_raw(msg) {
+            Ok(value) => Ok(value.status),
+            Err(err) => {
+                error!("{}", err);
+                Err(PowerMgmtError::Internal { reason: format!("Parsing Response (SetPowerConfigResponse) from slot {} failed", slot) }.into())
+            }
+        }
+    }
+}
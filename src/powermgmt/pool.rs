@@ -0,0 +1,261 @@
+use std::collections::{HashSet, VecDeque};
+use std::sync::{Arc, Condvar, Mutex};
+use std::sync::atomic::Ordering;
+use std::thread;
+
+use crate::powermgmt::{PowerMgmt, PowerMgmtState};
+use crate::settings;
+use crate::watchdog;
+
+/// Work handed to a worker thread
+pub type Job = Box<dyn FnOnce(&mut PowerMgmt) + Send + 'static>;
+
+/// Job together with the slots it works on
+struct Task {
+    slots: Vec<u8>,
+    job: Job,
+}
+
+/// Jobs which were submitted but not taken by a worker yet.
+struct Queue {
+    /// Slots of the running and ready jobs
+    busy: HashSet<u8>,
+    /// Jobs a worker can take
+    ready: VecDeque<Task>,
+    /// Jobs for a busy slot in the order they were submitted
+    waiting: VecDeque<Task>,
+    /// Set once the pool was dropped
+    closed: bool,
+}
+
+impl Queue {
+    fn len(&self) -> usize {
+        self.ready.len() + self.waiting.len()
+    }
+
+    fn add(&mut self, task: Task) {
+        let blocked = task.slots.iter().any(|slot| self.busy.contains(slot) || self.waiting.iter().any(|waiting| waiting.slots.contains(slot)));
+        match blocked {
+            true => self.waiting.push_back(task),
+            false => {
+                self.busy.extend(task.slots.iter().copied());
+                self.ready.push_back(task);
+            }
+        }
+    }
+
+    /// Frees the slots of a finished job, returns true if waiting jobs became ready.
+    ///
+    /// A job never overtakes an older one for the same slot.
+    fn release(&mut self, slots: &[u8]) -> bool {
+        for slot in slots {
+            self.busy.remove(slot);
+        }
+
+        let mut blocked: HashSet<u8> = HashSet::new();
+        let mut released = false;
+        for task in std::mem::take(&mut self.waiting) {
+            match task.slots.iter().any(|slot| self.busy.contains(slot) || blocked.contains(slot)) {
+                true => {
+                    blocked.extend(task.slots.iter().copied());
+                    self.waiting.push_back(task);
+                }
+                false => {
+                    self.busy.extend(task.slots.iter().copied());
+                    self.ready.push_back(task);
+                    released = true;
+                }
+            }
+        }
+        released
+    }
+}
+
+/// Queue shared by the pool and its workers
+struct Shared {
+    queue: Mutex<Queue>,
+    available: Condvar,
+}
+
+impl Shared {
+    /// Waits for a job, returns `None` if there was none within a beat interval.
+    /// Returns `Err` once the pool was dropped and every job was taken.
+    fn next(&self) -> Result<Option<Task>, ()> {
+        let mut queue = self.queue.lock().expect("Could not lock job queue");
+        if queue.ready.is_empty() && !queue.closed {
+            queue = self.available.wait_timeout(queue, watchdog::BEAT_INTERVAL).expect("Could not lock job queue").0;
+        }
+        match queue.ready.pop_front() {
+            Some(task) => Ok(Some(task)),
+            None if queue.closed && queue.waiting.is_empty() => Err(()),
+            None => Ok(None),
+        }
+    }
+
+    fn release(&self, slots: &[u8]) {
+        if self.queue.lock().expect("Could not lock job queue").release(slots) {
+            self.available.notify_all();
+        }
+    }
+}
+
+/// Fixed number of worker threads taking jobs from a bounded queue.
+///
+/// The driver runs with a small memory limit, so a burst of requests must not start a thread per request.
+/// Jobs for the same slot run one after the other: a job for a busy slot waits in the queue instead of
+/// blocking a worker, so jobs for other slots keep running in parallel.
+/// Every worker has a heartbeat, the watchdog detects a request that hangs by its age.
+/// Dropping the pool lets the workers finish the queued jobs, they stop once the queue is empty.
+pub struct WorkerPool {
+    state: Arc<PowerMgmtState>,
+    shared: Arc<Shared>,
+    capacity: usize,
+}
+
+impl WorkerPool {
+    pub fn start(state: Arc<PowerMgmtState>, workers: &settings::Workers) -> WorkerPool {
+        let queue = Queue { busy: HashSet::new(), ready: VecDeque::new(), waiting: VecDeque::new(), closed: false };
+        let shared = Arc::new(Shared { queue: Mutex::new(queue), available: Condvar::new() });
+
+        for index in 0..workers.threads {
+            let shared = shared.clone();
+            let mut worker = PowerMgmt::new(state.clone());
+            let name = format!("PowerMgmt worker {}", index);
+            let spawned = thread::Builder::new().name(name.clone()).spawn(move || {
+                let heartbeat = watchdog::register_worker(&name);
+                loop {
+                    heartbeat.beat();
+                    match shared.next() {
+                        Ok(Some(task)) => {
+                            heartbeat.beat();
+                            (task.job)(&mut worker);
+                            shared.release(&task.slots);
+                            worker.state.in_flight.fetch_sub(1, Ordering::SeqCst);
+                        }
+                        Ok(None) => continue,
+                        Err(_) => break,
+                    }
+                }
+                watchdog::unregister(&heartbeat);
+            });
+            if let Err(err) = spawned {
+                error!("Could not start PowerMgmt worker {}: {}", index, err);
+            }
+        }
+
+        WorkerPool { state, shared, capacity: workers.queue }
+    }
+
+    /// Queues a job working on `slots`, returns it if the queue is full.
+    pub fn submit(&self, slots: Vec<u8>, job: Job) -> Result<(), Job> {
+        let mut queue = self.shared.queue.lock().expect("Could not lock job queue");
+        if queue.len() >= self.capacity {
+            return Err(job);
+        }
+        self.state.in_flight.fetch_add(1, Ordering::SeqCst);
+        queue.add(Task { slots, job });
+        drop(queue);
+        self.shared.available.notify_all();
+        Ok(())
+    }
+}
+
+impl Drop for WorkerPool {
+    fn drop(&mut self) {
+        self.shared.queue.lock().expect("Could not lock job queue").closed = true;
+        self.shared.available.notify_all();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::{Duration, Instant};
+
+    use crossbeam_channel::{bounded, Receiver, Sender};
+
+    use super::*;
+    use crate::powermgmt::testing::Harness;
+
+    const TIMEOUT: Duration = Duration::from_secs(1);
+
+    /// Job which reports `id` once `release` was signaled.
+    fn blocking(id: u8, release: Receiver<()>, done: Sender<u8>) -> Job {
+        Box::new(move |_| {
+            release.recv().unwrap();
+            done.send(id).unwrap();
+        })
+    }
+
+    fn reporting(id: u8, done: Sender<u8>) -> Job {
+        Box::new(move |_| done.send(id).unwrap())
+    }
+
+    /// Waits until the workers took every ready job.
+    fn wait_taken(pool: &WorkerPool) {
+        while !pool.shared.queue.lock().unwrap().ready.is_empty() {
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn refuses_jobs_beyond_the_queue() {
+        let harness = Harness::new((10_000, 10_000, 10_000));
+        let pool = WorkerPool::start(harness.state.clone(), &settings::Workers { threads: 1, queue: 1 });
+        let (release_tx, release_rx) = bounded::<()>(0);
+        let (done_tx, done_rx) = bounded::<u8>(2);
+
+        assert!(pool.submit(Vec::new(), blocking(1, release_rx, done_tx.clone())).is_ok());
+        wait_taken(&pool);
+        assert!(pool.submit(Vec::new(), reporting(2, done_tx.clone())).is_ok());
+        assert!(pool.submit(Vec::new(), Box::new(|_| ())).is_err());
+        assert_eq!(harness.state.in_flight.load(Ordering::SeqCst), 2);
+
+        release_tx.send(()).unwrap();
+        assert_eq!(done_rx.recv_timeout(TIMEOUT).unwrap(), 1);
+        assert_eq!(done_rx.recv_timeout(TIMEOUT).unwrap(), 2);
+        // The worker counts a job as done after it returned
+        let deadline = Instant::now() + TIMEOUT;
+        while harness.state.in_flight.load(Ordering::SeqCst) != 0 && Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(1));
+        }
+        assert_eq!(harness.state.in_flight.load(Ordering::SeqCst), 0);
+    }
+
+    #[test]
+    fn jobs_for_different_slots_run_in_parallel() {
+        let harness = Harness::new((10_000, 10_000, 10_000));
+        let pool = WorkerPool::start(harness.state.clone(), &settings::Workers { threads: 2, queue: 4 });
+        let (release_tx, release_rx) = bounded::<()>(0);
+        let (done_tx, done_rx) = bounded::<u8>(2);
+
+        assert!(pool.submit(vec![1], blocking(1, release_rx, done_tx.clone())).is_ok());
+        assert!(pool.submit(vec![2], reporting(2, done_tx.clone())).is_ok());
+
+        // Slot 2 finishes while slot 1 is still running
+        assert_eq!(done_rx.recv_timeout(TIMEOUT).unwrap(), 2);
+        release_tx.send(()).unwrap();
+        assert_eq!(done_rx.recv_timeout(TIMEOUT).unwrap(), 1);
+    }
+
+    #[test]
+    fn jobs_for_the_same_slot_wait_without_taking_a_worker() {
+        let harness = Harness::new((10_000, 10_000, 10_000));
+        let pool = WorkerPool::start(harness.state.clone(), &settings::Workers { threads: 2, queue: 4 });
+        let (release_tx, release_rx) = bounded::<()>(0);
+        let (done_tx, done_rx) = bounded::<u8>(4);
+
+        assert!(pool.submit(vec![1], blocking(1, release_rx, done_tx.clone())).is_ok());
+        assert!(pool.submit(vec![1], reporting(2, done_tx.clone())).is_ok());
+        assert!(pool.submit(vec![1, 2], reporting(3, done_tx.clone())).is_ok());
+        assert!(pool.submit(vec![3], reporting(4, done_tx.clone())).is_ok());
+
+        // The second worker is free for slot 3, the jobs for slot 1 wait for the first one
+        assert_eq!(done_rx.recv_timeout(TIMEOUT).unwrap(), 4);
+        assert!(done_rx.recv_timeout(Duration::from_millis(50)).is_err());
+
+        release_tx.send(()).unwrap();
+        assert_eq!(done_rx.recv_timeout(TIMEOUT).unwrap(), 1);
+        assert_eq!(done_rx.recv_timeout(TIMEOUT).unwrap(), 2);
+        assert_eq!(done_rx.recv_timeout(TIMEOUT).unwrap(), 3);
+    }
+}
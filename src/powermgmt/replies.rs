@@ -0,0 +1,234 @@
+use std::collections::{HashMap, VecDeque};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time::Instant;
+
+use crossbeam_channel::Sender;
+use noreya_sdbp::util::*;
+
+use crate::settings;
+
+/// Command sent to a module whose reply is outstanding.
+struct PendingReply {
+    seq: u64,
+    reply_tx: Sender<PMsg>,
+    /// Set once the worker stopped waiting, a late reply is discarded
+    abandoned: Option<Instant>,
+}
+
+/// Outstanding commands per device id in the order they were sent.
+pub struct Replies {
+    /// Virtual device the commands are sent from, replies are addressed to it
+    vdev_id: u16,
+    pending: Mutex<HashMap<u16, VecDeque<PendingReply>>>,
+    next_seq: AtomicU64,
+}
+
+impl Replies {
+    pub fn new(vdev_id: u16) -> Replies {
+        Replies { vdev_id, pending: Mutex::new(HashMap::new()), next_seq: AtomicU64::new(0) }
+    }
+
+    /// Registers a command sent to a module, the reply is handed to `reply_tx`.
+    ///
+    /// Timed out commands of the module that were not answered within another reply timeout
+    /// are dropped first, the module is assumed to have lost them.
+    pub fn expect(&self, dev_id: u16, reply_tx: Sender<PMsg>) -> u64 {
+        let seq = self.next_seq.fetch_add(1, Ordering::SeqCst);
+        let expiry = settings::current().timeouts.device_reply();
+        let mut pending = self.pending.lock().expect("Could not lock replies");
+        let queue = pending.entry(dev_id).or_default();
+        let expired = queue.iter().all(|reply| matches!(reply.abandoned, Some(at) if at.elapsed() >= expiry));
+        if expired && !queue.is_empty() {
+            debug!("Slot {}: dropping {} expired commands", dev_id, queue.len());
+            queue.clear();
+        }
+        queue.push_back(PendingReply { seq, reply_tx, abandoned: None });
+        seq
+    }
+
+    /// Removes a command that never reached the module.
+    pub fn cancel(&self, dev_id: u16, seq: u64) {
+        let mut pending = self.pending.lock().expect("Could not lock replies");
+        if let Some(queue) = pending.get_mut(&dev_id) {
+            queue.retain(|reply| reply.seq != seq);
+            if queue.is_empty() {
+                pending.remove(&dev_id);
+            }
+        }
+    }
+
+    /// Marks a command as timed out, returns false if its reply was delivered in the meantime.
+    pub fn abandon(&self, dev_id: u16, seq: u64) -> bool {
+        let mut pending = self.pending.lock().expect("Could not lock replies");
+        let reply = pending.get_mut(&dev_id).and_then(|queue| queue.iter_mut().find(|reply| reply.seq == seq));
+        match reply {
+            Some(reply) => {
+                reply.abandoned = Some(Instant::now());
+                true
+            }
+            None => false,
+        }
+    }
+
+    #[cfg(test)]
+    pub fn has_pending(&self, dev_id: u16) -> bool {
+        self.pending.lock().expect("Could not lock replies").contains_key(&dev_id)
+    }
+
+    /// Hands a module reply to its oldest outstanding command.
+    ///
+    /// Modules answer their commands in order, so the oldest command gets the reply even if its worker
+    /// timed out, then the reply is discarded. A timed out command is only dropped without reply
+    /// by [`Replies::expect`] once nothing newer is queued behind it, otherwise the late reply
+    /// would be handed to the next command.
+    pub fn deliver(&self, msg: PMsg) {
+        let src = msg.get_src();
+        if msg.get_dst() != self.vdev_id {
+            warn!("Slot {}: discarding message addressed to device {}", src, msg.get_dst());
+            return;
+        }
+        let mut pending = self.pending.lock().expect("Could not lock replies");
+
+        let reply = match pending.get_mut(&src) {
+            Some(queue) => {
+                let reply = queue.pop_front();
+                if queue.is_empty() {
+                    pending.remove(&src);
+                }
+                reply
+            }
+            None => None,
+        };
+
+        match reply {
+            None => warn!("Slot {}: discarding unsolicited message", src),
+            Some(reply) if reply.abandoned.is_some() => warn!("Slot {}: discarding stale reply to command {}", src, reply.seq),
+            Some(reply) => {
+                if reply.reply_tx.send(msg).is_err() {
+                    warn!("Slot {}: discarding stale reply to command {}", src, reply.seq);
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use crossbeam_channel::{Receiver, bounded};
+
+    use super::*;
+    use crate::powermgmt::testing::install_settings;
+
+    const VDEV: u16 = 0x2001;
+    const SLOT: u16 = 1;
+
+    fn replies() -> Replies {
+        install_settings();
+        Replies::new(VDEV)
+    }
+
+    fn expect(replies: &Replies) -> (u64, Receiver<PMsg>) {
+        let (reply_tx, reply_rx) = bounded(1);
+        (replies.expect(SLOT, reply_tx), reply_rx)
+    }
+
+    fn reply(payload: u8) -> PMsg {
+        PMsg::create(SLOT, VDEV, Ok(vec![payload]))
+    }
+
+    #[test]
+    fn replies_are_handed_out_in_order() {
+        let replies = replies();
+        let (_, first) = expect(&replies);
+        let (_, second) = expect(&replies);
+
+        replies.deliver(reply(1));
+        replies.deliver(reply(2));
+
+        assert_eq!(first.try_recv().unwrap().get_msg(), Some(vec![1]));
+        assert_eq!(second.try_recv().unwrap().get_msg(), Some(vec![2]));
+        assert!(!replies.has_pending(SLOT));
+    }
+
+    #[test]
+    fn late_reply_to_abandoned_command_is_discarded() {
+        let replies = replies();
+        let (first_seq, first) = expect(&replies);
+        let (_, second) = expect(&replies);
+        assert!(replies.abandon(SLOT, first_seq));
+
+        replies.deliver(reply(1));
+        assert!(first.try_recv().is_err());
+        assert!(second.try_recv().is_err());
+
+        replies.deliver(reply(2));
+        assert_eq!(second.try_recv().unwrap().get_msg(), Some(vec![2]));
+    }
+
+    #[test]
+    fn abandoned_command_does_not_expire_while_newer_are_queued() {
+        let replies = replies();
+        let (first_seq, _) = expect(&replies);
+        let (_, second) = expect(&replies);
+        replies.abandon(SLOT, first_seq);
+        thread::sleep(settings::current().timeouts.device_reply() + Duration::from_millis(10));
+
+        // The late reply to the first command must not be taken for the second one
+        replies.deliver(reply(1));
+        assert!(second.try_recv().is_err());
+        replies.deliver(reply(2));
+        assert_eq!(second.try_recv().unwrap().get_msg(), Some(vec![2]));
+    }
+
+    #[test]
+    fn expired_command_is_dropped_before_the_next_one() {
+        let replies = replies();
+        let (first_seq, _) = expect(&replies);
+        replies.abandon(SLOT, first_seq);
+        thread::sleep(settings::current().timeouts.device_reply() + Duration::from_millis(10));
+
+        let (_, second) = expect(&replies);
+        replies.deliver(reply(2));
+        assert_eq!(second.try_recv().unwrap().get_msg(), Some(vec![2]));
+    }
+
+    #[test]
+    fn abandoned_command_within_expiry_is_kept() {
+        let replies = replies();
+        let (first_seq, _) = expect(&replies);
+        replies.abandon(SLOT, first_seq);
+
+        let (_, second) = expect(&replies);
+        replies.deliver(reply(1));
+        assert!(second.try_recv().is_err());
+        replies.deliver(reply(2));
+        assert_eq!(second.try_recv().unwrap().get_msg(), Some(vec![2]));
+    }
+
+    #[test]
+    fn reply_to_other_device_is_discarded() {
+        let replies = replies();
+        let (_, first) = expect(&replies);
+
+        replies.deliver(PMsg::create(SLOT, VDEV + 1, Ok(vec![1])));
+        assert!(first.try_recv().is_err());
+        assert!(replies.has_pending(SLOT));
+
+        replies.deliver(reply(2));
+        assert_eq!(first.try_recv().unwrap().get_msg(), Some(vec![2]));
+    }
+
+    #[test]
+    fn unsolicited_reply_is_discarded() {
+        let replies = replies();
+        replies.deliver(reply(1));
+
+        let (_, first) = expect(&replies);
+        replies.deliver(reply(2));
+        assert_eq!(first.try_recv().unwrap().get_msg(), Some(vec![2]));
+    }
+}
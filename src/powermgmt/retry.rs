@@ -0,0 +1,36 @@
+use std::io::Error;
+use std::thread;
+use std::time::Duration;
+
+use crate::powermgmt::error::PowerMgmtError;
+use crate::settings::{self, RetryPolicy};
+
+/// Runs `step` until it succeeds, fails with an error the policy does not retry or runs out of attempts.
+///
+/// A module which timed out is retried after the reply timeout at the earliest: until then
+/// [`Replies`](crate::powermgmt::replies::Replies) waits for the reply to the lost command
+/// and would discard the reply to the retried one.
+pub fn run<T, F: FnMut() -> Result<T, Error>>(name: &str, slot: u8, policy: &RetryPolicy, mut step: F) -> Result<T, Error> {
+    let max_backoff = Duration::from_millis(policy.max_backoff_ms);
+    let mut backoff = Duration::from_millis(policy.backoff_ms).min(max_backoff);
+    let mut attempt = 1;
+    loop {
+        let err = match step() {
+            Ok(value) => return Ok(value),
+            Err(err) => err,
+        };
+
+        let retryable = policy.retry_on.iter().any(|error| error == PowerMgmtError::from_io(&err).name());
+        if !retryable || attempt >= policy.max_attempts {
+            return Err(err);
+        }
+        let delay = match PowerMgmtError::from_io(&err) {
+            PowerMgmtError::Timeout { .. } => backoff.max(settings::current().timeouts.device_reply()),
+            _ => backoff,
+        };
+        warn!("Slot {}: {} failed (attempt {} of {}), retrying in {:?}: {}", slot, name, attempt, policy.max_attempts, delay, err);
+        thread::sleep(delay);
+        backoff = (backoff * 2).min(max_backoff);
+        attempt += 1;
+    }
+}
@@ -0,0 +1,60 @@
+use std::collections::HashSet;
+use std::io::Error;
+use std::path::PathBuf;
+
+use crate::powermgmt::error::PowerMgmtError;
+use crate::settings::Settings;
+
+/// Tells whether a module is connected to a slot.
+pub trait SlotResolver: Send {
+    fn is_connected(&self, slot: u8) -> bool;
+
+    fn check_connected(&self, slot: u8) -> Result<(), Error> {
+        match self.is_connected(slot) {
+            true => Ok(()),
+            false => Err(PowerMgmtError::SlotNotConnected { slot }.into()),
+        }
+    }
+}
+
+/// Looks up slots in the sysfs tree of the SDBPK kernel driver (`<root>/slotN`).
+pub struct SysfsSlots {
+    root: PathBuf,
+}
+
+impl SysfsSlots {
+    pub fn new(root: PathBuf) -> SysfsSlots {
+        SysfsSlots { root }
+    }
+}
+
+impl SlotResolver for SysfsSlots {
+    fn is_connected(&self, slot: u8) -> bool {
+        self.root.join(format!("slot{}", slot)).exists()
+    }
+}
+
+/// Fixed set of connected slots.
+pub struct StaticSlots {
+    slots: HashSet<u8>,
+}
+
+impl StaticSlots {
+    pub fn new<I: IntoIterator<Item = u8>>(slots: I) -> StaticSlots {
+        StaticSlots { slots: slots.into_iter().collect() }
+    }
+}
+
+impl SlotResolver for StaticSlots {
+    fn is_connected(&self, slot: u8) -> bool {
+        self.slots.contains(&slot)
+    }
+}
+
+/// Uses the static slots if configured, sysfs otherwise.
+pub fn from_settings(settings: &Settings) -> Box<dyn SlotResolver> {
+    match &settings.static_slots {
+        Some(slots) => Box::new(StaticSlots::new(slots.iter().copied())),
+        None => Box::new(SysfsSlots::new(PathBuf::from(&settings.sysfs_root))),
+    }
+}
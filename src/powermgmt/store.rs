@@ -0,0 +1,137 @@
+use std::collections::HashMap;
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::PathBuf;
+
+/// Last committed pin config of every module, keyed by module UID.
+///
+/// The state file contains one `<uid> <pin records as hex>` line per module,
+/// the pin records use the wire format of [`PowerConfig`](super::data::PowerConfig).
+/// A module without pins has an empty hex part.
+pub struct ConfigStore {
+    path: PathBuf,
+    entries: HashMap<String, Vec<u8>>,
+}
+
+impl ConfigStore {
+    pub fn open(path: PathBuf) -> ConfigStore {
+        let mut store = ConfigStore { path, entries: HashMap::new() };
+        match store.load() {
+            Ok(_) => debug!("Loaded {} power configs from {}", store.entries.len(), store.path.display()),
+            Err(err) if err.kind() == ErrorKind::NotFound => (),
+            Err(err) => error!("Could not load power configs from {}: {}", store.path.display(), err),
+        }
+        store
+    }
+
+    pub fn load(&mut self) -> Result<(), Error> {
+        let content = match fs::read_to_string(&self.path) {
+            Ok(value) => value,
+            Err(err) => return Err(err),
+        };
+
+        let mut entries = HashMap::new();
+        for (number, line) in content.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() {
+                continue;
+            }
+            let (uid, hex) = line.split_once(' ').unwrap_or((line, ""));
+            match decode_hex(hex.trim()) {
+                Some(payload) => {
+                    entries.insert(uid.to_string(), payload);
+                }
+                None => warn!("{}:{}: ignoring malformed entry", self.path.display(), number + 1),
+            }
+        }
+        self.entries = entries;
+        Ok(())
+    }
+
+    pub fn get(&self, uid: &str) -> Option<&Vec<u8>> {
+        self.entries.get(uid)
+    }
+
+    pub fn set(&mut self, uid: String, payload: Vec<u8>) -> Result<(), Error> {
+        if self.entries.get(&uid) == Some(&payload) {
+            return Ok(());
+        }
+        self.entries.insert(uid, payload);
+        self.save()
+    }
+
+    /// Writes the state file atomically so a crash never leaves a partial file behind.
+    fn save(&self) -> Result<(), Error> {
+        if let Some(dir) = self.path.parent() {
+            match fs::create_dir_all(dir) {
+                Ok(_) => (),
+                Err(err) => return Err(err),
+            }
+        }
+
+        let mut uids: Vec<&String> = self.entries.keys().collect();
+        uids.sort();
+        let mut content = String::new();
+        for uid in uids {
+            content.push_str(&format!("{} {}\n", uid, encode_hex(&self.entries[uid])));
+        }
+
+        let tmp = self.path.with_extension("tmp");
+        match fs::write(&tmp, content) {
+            Ok(_) => (),
+            Err(err) => return Err(Error::new(err.kind(), format!("Writing {} failed: {}", tmp.display(), err))),
+        }
+        fs::rename(&tmp, &self.path)
+    }
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    if text.len() % 2 != 0 || !text.is_ascii() {
+        return None;
+    }
+    let mut bytes = Vec::with_capacity(text.len() / 2);
+    for i in (0..text.len()).step_by(2) {
+        match u8::from_str_radix(&text[i..i + 2], 16) {
+            Ok(value) => bytes.push(value),
+            Err(_) => return None,
+        }
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use tempfile::TempDir;
+
+    use super::*;
+
+    #[test]
+    fn configs_survive_a_restart() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("state").join("power-config");
+        let mut store = ConfigStore::open(path.clone());
+        store.set("io-1".to_string(), vec![0x01, 0x05, 0x00, 0x64]).unwrap();
+        store.set("io-2".to_string(), Vec::new()).unwrap();
+
+        let reopened = ConfigStore::open(path);
+        assert_eq!(reopened.get("io-1"), Some(&vec![0x01, 0x05, 0x00, 0x64]));
+        assert_eq!(reopened.get("io-2"), Some(&Vec::new()));
+    }
+
+    #[test]
+    fn malformed_entries_are_skipped() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("power-config");
+        fs::write(&path, "io-1 0105\nio-2 zz\n\nio-3 010\nio-4\n").unwrap();
+
+        let store = ConfigStore::open(path);
+        assert_eq!(store.get("io-1"), Some(&vec![0x01, 0x05]));
+        assert_eq!(store.get("io-2"), None);
+        assert_eq!(store.get("io-3"), None);
+        assert_eq!(store.get("io-4"), Some(&Vec::new())); // Trailing space stripped by an editor
+    }
+}
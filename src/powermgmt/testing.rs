@@ -0,0 +1,638 @@
+//! Hermetic stand-ins for the driver environment of the PowerMgmt tests.
+//!
+//! The slots are looked up by [`SysfsSlots`] in a fake sysfs tree in a temp dir.
+//!
+//! [`Harness`] replaces the IO modules and power-mgmt behind [`Modules`] and [`Connector`]
+//! to test the request logic. [`Bus`] stands in for the dispatcher instead: the commands of
+//! [`SdbpModules`] reach simulated modules as frames and their replies are routed by the [`Router`]
+//! like on the PowerMgmt thread. [`PowerMgmtSocket`] listens on the power-mgmt socket of the settings.
+
+use std::collections::{HashMap, VecDeque};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::os::unix::net::UnixListener;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex, Once};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread;
+use std::time::Duration;
+
+use crossbeam_channel::{Receiver, Sender};
+use noreya_sdbp::datatypes::Version;
+use noreya_sdbp::drv::core::{SharedStats, Stats};
+use noreya_sdbp::util::PMsg;
+use tempfile::TempDir;
+
+use crate::powermgmt::{PowerMgmt, PowerMgmtState, Router, VDEV_ID};
+use crate::powermgmt::audit::AuditLog;
+use crate::powermgmt::client::{Connection, Connector, Shortfall};
+use crate::powermgmt::data::{MilliWatts, PinConfig, PowerConfig};
+use crate::powermgmt::error::PowerMgmtError;
+use crate::powermgmt::module::{FirmwareStatus, ModuleInfo, Modules, SdbpModules};
+use crate::powermgmt::pool::WorkerPool;
+use crate::powermgmt::replies::Replies;
+use crate::powermgmt::slots::SysfsSlots;
+use crate::powermgmt::store::ConfigStore;
+use crate::settings::{self, Audit, RetryPolicy, Settings, Workers};
+
+/// Device id of the client sending the requests
+pub const CLIENT: u16 = 0x1000;
+
+/// Module command a test can make fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    /// Descriptor lookup, not recorded in [`FakeModules::calls`]
+    Info,
+    Suspend,
+    Descriptor,
+    Test,
+    Set,
+}
+
+/// Scripted answer replacing the next regular one
+#[derive(Debug, Clone)]
+enum Fault {
+    Error(PowerMgmtError),
+    Status(FirmwareStatus),
+    /// Answers regularly after the delay
+    Delay(Duration),
+}
+
+/// Simulated IO module.
+///
+/// While suspended it reports its idle power in the descriptor,
+/// while running the load of its active pins is included.
+#[derive(Debug, Clone)]
+pub struct FakeModule {
+    pub uid: String,
+    pub idle: (u16, u16, u16),
+    pub pins: Vec<(u8, u16)>,
+    pub suspended: bool,
+}
+
+impl FakeModule {
+    fn info(&self) -> ModuleInfo {
+        let mut info = ModuleInfo {
+            uid: self.uid.clone(),
+            max_power_3v3: MilliWatts::from(self.idle.0),
+            max_power_5v0: MilliWatts::from(self.idle.1),
+            max_power_12v: MilliWatts::from(self.idle.2),
+        };
+        if !self.suspended {
+            let pins = self.pins.iter().map(|(voltage, current)| PinConfig::new(*voltage, *current)).collect();
+            let load = PowerConfig::from_pins(0, pins).expect("Fake module has invalid pins").pin_load().expect("Fake module load overflows");
+            info.max_power_3v3 = info.max_power_3v3.checked_add(load.power_3v3).expect("Fake module power overflows");
+            info.max_power_5v0 = info.max_power_5v0.checked_add(load.power_5v0).expect("Fake module power overflows");
+            info.max_power_12v = info.max_power_12v.checked_add(load.power_12v).expect("Fake module power overflows");
+        }
+        info
+    }
+}
+
+/// Simulated IO modules answering the commands of the driver, faults are scripted per slot and step.
+#[derive(Default)]
+pub struct FakeModules {
+    modules: Mutex<HashMap<u8, FakeModule>>,
+    /// Slot, step, calls to let pass before the fault and the fault
+    faults: Mutex<Vec<(u8, Step, usize, Fault)>>,
+    calls: Mutex<Vec<(u8, Step)>>,
+}
+
+impl FakeModules {
+    pub fn insert(&self, slot: u8, uid: &str, idle: (u16, u16, u16)) {
+        let module = FakeModule { uid: uid.to_string(), idle, pins: Vec::new(), suspended: false };
+        self.modules.lock().unwrap().insert(slot, module);
+    }
+
+    pub fn remove(&self, slot: u8) {
+        self.modules.lock().unwrap().remove(&slot);
+    }
+
+    pub fn module(&self, slot: u8) -> FakeModule {
+        self.modules.lock().unwrap()[&slot].clone()
+    }
+
+    /// Makes the next `step` command for the slot fail with `err`.
+    pub fn fail(&self, slot: u8, step: Step, err: PowerMgmtError) {
+        self.fail_later(slot, step, 0, err);
+    }
+
+    /// Makes the `step` command for the slot fail with `err` after `skip` successful ones.
+    pub fn fail_later(&self, slot: u8, step: Step, skip: usize, err: PowerMgmtError) {
+        self.faults.lock().unwrap().push((slot, step, skip, Fault::Error(err)));
+    }
+
+    /// Makes the firmware answer the next `step` command for the slot with `status`.
+    pub fn reject(&self, slot: u8, step: Step, status: FirmwareStatus) {
+        self.faults.lock().unwrap().push((slot, step, 0, Fault::Status(status)));
+    }
+
+    /// Makes the module take `delay` to answer the next `step` command for the slot.
+    pub fn delay(&self, slot: u8, step: Step, delay: Duration) {
+        self.faults.lock().unwrap().push((slot, step, 0, Fault::Delay(delay)));
+    }
+
+    /// Commands the slot received in order.
+    pub fn calls(&self, slot: u8) -> Vec<Step> {
+        self.calls.lock().unwrap().iter().filter(|(called, _)| *called == slot).map(|(_, step)| *step).collect()
+    }
+
+    fn call(&self, slot: u8, step: Step) -> Result<Option<FirmwareStatus>, Error> {
+        self.calls.lock().unwrap().push((slot, step));
+        if !self.modules.lock().unwrap().contains_key(&slot) {
+            return Err(PowerMgmtError::Timeout { slot }.into());
+        }
+        self.fault(slot, step)
+    }
+
+    fn fault(&self, slot: u8, step: Step) -> Result<Option<FirmwareStatus>, Error> {
+        let mut faults = self.faults.lock().unwrap();
+        let index = match faults.iter().position(|(faulty, faulty_step, _, _)| *faulty == slot && *faulty_step == step) {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        if faults[index].2 > 0 {
+            faults[index].2 -= 1;
+            return Ok(None);
+        }
+        match faults.remove(index).3 {
+            Fault::Error(err) => Err(err.into()),
+            Fault::Status(status) => Ok(Some(status)),
+            Fault::Delay(delay) => {
+                drop(faults);
+                thread::sleep(delay);
+                Ok(None)
+            }
+        }
+    }
+}
+
+impl Modules for FakeModules {
+    fn present(&self) -> HashMap<u8, String> {
+        self.modules.lock().unwrap().iter().map(|(slot, module)| (*slot, module.uid.clone())).collect()
+    }
+
+    fn info(&self, slot: u8) -> Result<ModuleInfo, Error> {
+        match self.fault(slot, Step::Info) {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        }
+        match self.modules.lock().unwrap().get(&slot) {
+            Some(module) => Ok(module.info()),
+            None => Err(PowerMgmtError::Internal { reason: format!("Module in slot {} is not enumerated yet", slot) }.into()),
+        }
+    }
+
+    fn suspend(&self, slot: u8) -> Result<(), Error> {
+        match self.call(slot, Step::Suspend) {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        }
+        self.modules.lock().unwrap().get_mut(&slot).unwrap().suspended = true;
+        Ok(())
+    }
+
+    fn update_descriptor(&self, slot: u8) -> Result<ModuleInfo, Error> {
+        match self.call(slot, Step::Descriptor) {
+            Ok(_) => (),
+            Err(err) => return Err(err),
+        }
+        let mut modules = self.modules.lock().unwrap();
+        let module = modules.get_mut(&slot).unwrap();
+        module.suspended = false;
+        Ok(module.info())
+    }
+
+    fn test_power_config(&self, slot: u8, _pins: &[(u8, u16)]) -> Result<FirmwareStatus, Error> {
+        match self.call(slot, Step::Test) {
+            Ok(status) => Ok(status.unwrap_or(0)),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn set_power_config(&self, slot: u8, pins: &[(u8, u16)]) -> Result<FirmwareStatus, Error> {
+        match self.call(slot, Step::Set) {
+            Ok(Some(status)) => Ok(status),
+            Ok(None) => {
+                self.modules.lock().unwrap().get_mut(&slot).unwrap().pins = pins.to_vec();
+                Ok(0)
+            }
+            Err(err) => Err(err),
+        }
+    }
+}
+
+/// Step of a power-mgmt connection a test can make fail
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PowerMgmtStep {
+    Connect,
+    Request,
+    /// Finishing fails with an error
+    Finish,
+    /// power-mgmt refuses to finish the request
+    Refuse,
+}
+
+struct FakePowerMgmtState {
+    /// Power available per rail to all slots together
+    capacity: (u32, u32, u32),
+    /// Finished budget per slot
+    budgets: HashMap<u8, (u16, u16, u16)>,
+    instance: u64,
+    /// Faulty step and the number of successful ones before it fails
+    faults: Vec<(PowerMgmtStep, usize)>,
+    requests: usize,
+    /// Connections holding an unfinished reservation
+    reservations: usize,
+    /// Set once the finished budgets exceeded the capacity
+    overcommitted: bool,
+}
+
+/// Simulated power-mgmt service.
+///
+/// A finished request replaces the budget of its slot, a request that is never finished
+/// (the connection is dropped) leaves it untouched. Requests are checked against the finished
+/// budgets only, so reservations made in parallel can exceed the capacity once finished,
+/// which is recorded as over-commit.
+#[derive(Clone)]
+pub struct FakePowerMgmt {
+    state: Arc<Mutex<FakePowerMgmtState>>,
+}
+
+impl FakePowerMgmt {
+    pub fn new(capacity: (u32, u32, u32)) -> FakePowerMgmt {
+        let state = FakePowerMgmtState { capacity, budgets: HashMap::new(), instance: 1, faults: Vec::new(), requests: 0, reservations: 0, overcommitted: false };
+        FakePowerMgmt { state: Arc::new(Mutex::new(state)) }
+    }
+
+    pub fn budget(&self, slot: u8) -> Option<(u16, u16, u16)> {
+        self.state.lock().unwrap().budgets.get(&slot).copied()
+    }
+
+    /// Number of budget requests received
+    pub fn requests(&self) -> usize {
+        self.state.lock().unwrap().requests
+    }
+
+    /// Number of unfinished reservations whose connection is still open
+    pub fn reservations(&self) -> usize {
+        self.state.lock().unwrap().reservations
+    }
+
+    /// Returns true if the finished budgets exceeded the capacity at any time.
+    pub fn overcommitted(&self) -> bool {
+        self.state.lock().unwrap().overcommitted
+    }
+
+    /// Makes the next `step` fail.
+    pub fn fail(&self, step: PowerMgmtStep) {
+        self.fail_later(step, 0);
+    }
+
+    /// Makes `step` fail after `skip` successful ones.
+    pub fn fail_later(&self, step: PowerMgmtStep, skip: usize) {
+        self.state.lock().unwrap().faults.push((step, skip));
+    }
+
+    /// Starts a new instance which forgot every budget.
+    pub fn restart(&self) {
+        let mut state = self.state.lock().unwrap();
+        state.budgets.clear();
+        state.instance += 1;
+    }
+
+    fn fault(&self, step: PowerMgmtStep) -> bool {
+        let mut state = self.state.lock().unwrap();
+        let index = match state.faults.iter().position(|(faulty, _)| *faulty == step) {
+            Some(value) => value,
+            None => return false,
+        };
+        if state.faults[index].1 > 0 {
+            state.faults[index].1 -= 1;
+            return false;
+        }
+        state.faults.remove(index);
+        true
+    }
+}
+
+impl Connector for FakePowerMgmt {
+    fn connect(&self) -> Result<Box<dyn Connection>, Error> {
+        if self.fault(PowerMgmtStep::Connect) {
+            return Err(Error::new(ErrorKind::ConnectionRefused, "power-mgmt not running"));
+        }
+        Ok(Box::new(FakeConnection { power_mgmt: self.clone(), pending: None }))
+    }
+
+    fn instance(&self) -> Option<(u64, u64)> {
+        Some((0, self.state.lock().unwrap().instance))
+    }
+}
+
+struct FakeConnection {
+    power_mgmt: FakePowerMgmt,
+    pending: Option<(u8, (u16, u16, u16))>,
+}
+
+impl Connection for FakeConnection {
+    fn request(&mut self, slot: u8, budget: (u16, u16, u16)) -> Result<Option<Shortfall>, Error> {
+        if self.power_mgmt.fault(PowerMgmtStep::Request) {
+            return Err(PowerMgmtError::PowerMgmtUnreachable { reason: "connection reset".to_string() }.into());
+        }
+        let mut state = self.power_mgmt.state.lock().unwrap();
+        state.requests += 1;
+
+        let mut used = (0u32, 0u32, 0u32);
+        for (_, other) in state.budgets.iter().filter(|(other, _)| **other != slot) {
+            used = (used.0 + other.0 as u32, used.1 + other.1 as u32, used.2 + other.2 as u32);
+        }
+        let excess = |used: u32, requested: u16, capacity: u32| (used + requested as u32).saturating_sub(capacity).min(u16::MAX as u32) as u16;
+        let shortfall = (excess(used.0, budget.0, state.capacity.0), excess(used.1, budget.1, state.capacity.1), excess(used.2, budget.2, state.capacity.2));
+        if shortfall != (0, 0, 0) {
+            return Ok(Some(shortfall));
+        }
+        if self.pending.replace((slot, budget)).is_none() {
+            state.reservations += 1;
+        }
+        Ok(None)
+    }
+
+    fn finish_request(&mut self) -> Result<bool, Error> {
+        if self.power_mgmt.fault(PowerMgmtStep::Finish) {
+            return Err(PowerMgmtError::PowerMgmtUnreachable { reason: "connection reset".to_string() }.into());
+        }
+        if self.power_mgmt.fault(PowerMgmtStep::Refuse) {
+            return Ok(false);
+        }
+        match self.pending.take() {
+            Some((slot, budget)) => {
+                let mut state = self.power_mgmt.state.lock().unwrap();
+                state.budgets.insert(slot, budget);
+                state.reservations -= 1;
+                let total = state.budgets.values().fold((0u32, 0u32, 0u32), |total, budget| (total.0 + budget.0 as u32, total.1 + budget.1 as u32, total.2 + budget.2 as u32));
+                if total.0 > state.capacity.0 || total.1 > state.capacity.1 || total.2 > state.capacity.2 {
+                    state.overcommitted = true;
+                }
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+impl Drop for FakeConnection {
+    fn drop(&mut self) {
+        if self.pending.is_some() {
+            self.power_mgmt.state.lock().unwrap().reservations -= 1;
+        }
+    }
+}
+
+static SETTINGS: Once = Once::new();
+
+/// Installs settings without waits and with quick retries, shared by all tests.
+pub fn install_settings() {
+    SETTINGS.call_once(|| {
+        let mut settings = Settings::default();
+        settings.timeouts.suspend_settle_ms = 0;
+        settings.timeouts.device_reply_ms = 50;
+        settings.timeouts.request_ms = 50;
+        let socket = std::env::temp_dir().join(format!("nexus-drv-io-test-{}", std::process::id())).join("power-mgmt.socket");
+        settings.power_mgmt_path = socket.to_string_lossy().to_string();
+        let policy = RetryPolicy { max_attempts: 3, backoff_ms: 1, max_backoff_ms: 1, ..RetryPolicy::default() };
+        settings.retry.suspend = policy.clone();
+        settings.retry.test = policy.clone();
+        settings.retry.set = policy.clone();
+        settings.retry.descriptor = policy.clone();
+        settings.retry.power_mgmt = policy;
+        settings::install(settings);
+    });
+}
+
+/// PowerMgmt device running against a fake sysfs tree, fake modules and a fake power-mgmt.
+pub struct Harness {
+    pub modules: Arc<FakeModules>,
+    pub power_mgmt: FakePowerMgmt,
+    pub state: Arc<PowerMgmtState>,
+    dir: TempDir,
+}
+
+impl Harness {
+    /// power-mgmt provides `capacity` mW per rail (3V3, 5V, 12V).
+    pub fn new(capacity: (u32, u32, u32)) -> Harness {
+        let modules = Arc::new(FakeModules::default());
+        let power_mgmt = FakePowerMgmt::new(capacity);
+        let (dir, state) = environment(modules.clone(), &power_mgmt);
+        Harness { modules, power_mgmt, state: Arc::new(state), dir }
+    }
+
+    /// Connects a module with the idle power (3V3, 5V, 12V in mW) to a slot.
+    pub fn plug(&self, slot: u8, uid: &str, idle: (u16, u16, u16)) {
+        fs::create_dir_all(sysfs(&self.dir).join(format!("slot{}", slot))).unwrap();
+        self.modules.insert(slot, uid, idle);
+    }
+
+    pub fn unplug(&self, slot: u8) {
+        fs::remove_dir_all(sysfs(&self.dir).join(format!("slot{}", slot))).unwrap();
+        self.modules.remove(slot);
+    }
+
+    pub fn worker(&self) -> PowerMgmt {
+        PowerMgmt::new(self.state.clone())
+    }
+
+    /// Sends a request frame as [`CLIENT`] and returns the response payload.
+    pub fn request(&self, frame: Vec<u8>) -> Result<Vec<u8>, Error> {
+        self.worker().power_management(CLIENT, Some(frame))
+    }
+
+    pub fn audit_log(&self) -> String {
+        fs::read_to_string(self.dir.path().join("log").join(settings::AUDIT_FILE)).unwrap_or_default()
+    }
+}
+
+fn sysfs(dir: &TempDir) -> PathBuf {
+    dir.path().join("sys/class/sdbp")
+}
+
+/// State of a PowerMgmt device with the fake sysfs tree, store and audit log in a temp dir.
+fn environment(modules: Arc<dyn Modules>, power_mgmt: &FakePowerMgmt) -> (TempDir, PowerMgmtState) {
+    install_settings();
+    let dir = tempfile::tempdir().expect("Could not create temp dir");
+    fs::create_dir_all(sysfs(&dir)).unwrap();
+    let audit = Audit { dir: dir.path().join("log").to_string_lossy().to_string(), ..Audit::default() };
+    fs::create_dir_all(&audit.dir).unwrap();
+
+    let store = ConfigStore::open(dir.path().join("state").join(settings::POWER_CONFIG_STATE_FILE));
+    let state = PowerMgmtState::with(modules, Box::new(SysfsSlots::new(sysfs(&dir))), Box::new(power_mgmt.clone()), store, AuditLog::new(&audit));
+    (dir, state)
+}
+
+/// Answer of a module on the [`Bus`] to its next command
+#[derive(Debug, Clone)]
+pub enum Answer {
+    /// Replies with the payload
+    Reply(Vec<u8>),
+    /// Loses the command
+    Lose,
+}
+
+/// Frames received by the modules with their slot
+type Frames = Vec<(u8, Vec<u8>)>;
+
+/// Dispatcher stand-in between the PowerMgmt device, simulated modules and clients.
+///
+/// Modules answer every command with an empty reply unless answers were scripted.
+/// Messages addressed to clients are collected in `responses`.
+pub struct Bus {
+    pub modules: Arc<SdbpModules>,
+    pub responses: Receiver<PMsg>,
+    /// Messages to the PowerMgmt device
+    inbox: Sender<PMsg>,
+    router: Arc<Mutex<Router>>,
+    answers: Arc<Mutex<HashMap<u8, VecDeque<Answer>>>>,
+    frames: Arc<Mutex<Frames>>,
+    stop: Arc<AtomicBool>,
+    dir: TempDir,
+}
+
+impl Bus {
+    pub fn new(capacity: (u32, u32, u32), workers: &Workers) -> Bus {
+        let (outbox, outbox_rx) = crossbeam_channel::unbounded::<PMsg>();
+        let (inbox, inbox_rx) = crossbeam_channel::unbounded::<PMsg>();
+        let (responses_tx, responses) = crossbeam_channel::unbounded::<PMsg>();
+
+        let version = || Version::from_str("00000.00001.00000").unwrap();
+        let shared = SharedStats::new(Stats::new(settings::MODULE_NAME.to_string(), version(), version()));
+        let replies = Arc::new(Replies::new(VDEV_ID));
+        let modules = Arc::new(SdbpModules::new(VDEV_ID, outbox.clone(), shared, replies.clone()));
+        let power_mgmt = FakePowerMgmt::new(capacity);
+        let (dir, state) = environment(modules.clone(), &power_mgmt);
+        let state = Arc::new(state);
+        let pool = WorkerPool::start(state.clone(), workers);
+        let router = Arc::new(Mutex::new(Router { tx: outbox, state: state.clone(), replies, pool, present: HashMap::new() }));
+
+        let bus = Bus {
+            modules,
+            responses,
+            inbox,
+            router,
+            answers: Arc::new(Mutex::new(HashMap::new())),
+            frames: Arc::new(Mutex::new(Vec::new())),
+            stop: Arc::new(AtomicBool::new(false)),
+            dir,
+        };
+
+        let (stop, answers, frames, inbox) = (bus.stop.clone(), bus.answers.clone(), bus.frames.clone(), bus.inbox.clone());
+        thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                let msg = match outbox_rx.recv_timeout(Duration::from_millis(10)) {
+                    Ok(value) => value,
+                    Err(_) => continue,
+                };
+                let dst = msg.get_dst();
+                if dst > u8::MAX as u16 {
+                    let _ = responses_tx.send(msg);
+                    continue;
+                }
+                frames.lock().unwrap().push((dst as u8, msg.get_msg().unwrap_or_default()));
+                let answer = answers.lock().unwrap().get_mut(&(dst as u8)).and_then(|queue| queue.pop_front());
+                match answer.unwrap_or(Answer::Reply(Vec::new())) {
+                    Answer::Reply(payload) => {
+                        let _ = inbox.send(PMsg::create(dst, msg.get_src(), Ok(payload)));
+                    }
+                    Answer::Lose => (),
+                }
+            }
+        });
+
+        let (stop, router) = (bus.stop.clone(), bus.router.clone());
+        thread::spawn(move || {
+            while !stop.load(Ordering::SeqCst) {
+                match inbox_rx.recv_timeout(Duration::from_millis(10)) {
+                    Ok(msg) => router.lock().unwrap().route(msg),
+                    Err(_) => continue,
+                }
+            }
+        });
+        bus
+    }
+
+    /// Connects a module to a slot, the router knows it as present.
+    pub fn plug(&self, slot: u8, uid: &str) {
+        fs::create_dir_all(sysfs(&self.dir).join(format!("slot{}", slot))).unwrap();
+        self.router.lock().unwrap().present.insert(slot, uid.to_string());
+    }
+
+    /// Scripts the answers of the module in a slot to its next commands.
+    pub fn script(&self, slot: u8, answers: Vec<Answer>) {
+        self.answers.lock().unwrap().entry(slot).or_default().extend(answers);
+    }
+
+    /// Frames the module in a slot received in order.
+    pub fn frames(&self, slot: u8) -> Vec<Vec<u8>> {
+        self.frames.lock().unwrap().iter().filter(|(to, _)| *to == slot).map(|(_, frame)| frame.clone()).collect()
+    }
+
+    /// Sends a message to the PowerMgmt device as the dispatcher would.
+    pub fn send(&self, msg: PMsg) {
+        assert!(self.inbox.send(msg).is_ok());
+    }
+}
+
+impl Drop for Bus {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::SeqCst);
+    }
+}
+
+/// power-mgmt stand-in listening on `power_mgmt_path` of the settings.
+///
+/// Connections are accepted by the backlog and never answered, the budget negotiation
+/// is simulated by [`FakePowerMgmt`].
+pub struct PowerMgmtSocket {
+    path: PathBuf,
+    listener: Option<UnixListener>,
+}
+
+impl PowerMgmtSocket {
+    pub fn start() -> PowerMgmtSocket {
+        install_settings();
+        let path = PathBuf::from(&settings::current().power_mgmt_path);
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        let _ = fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).expect("Could not bind power-mgmt socket");
+        PowerMgmtSocket { path, listener: Some(listener) }
+    }
+
+    /// Stops listening and removes the socket.
+    pub fn stop(&mut self) {
+        self.listener = None;
+        let _ = fs::remove_file(&self.path);
+    }
+
+    /// Replaces the socket like a restarted power-mgmt.
+    ///
+    /// The new socket is bound while the old one still exists, so it cannot get the inode of the old one.
+    pub fn restart(&mut self) {
+        let next = self.path.with_extension("next");
+        let listener = UnixListener::bind(&next).expect("Could not bind power-mgmt socket");
+        fs::rename(&next, &self.path).expect("Could not replace power-mgmt socket");
+        self.listener = Some(listener);
+    }
+}
+
+impl Drop for PowerMgmtSocket {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+/// Set request frame for a slot.
+pub fn set_frame(slot: u8, pins: &[(u8, u16)]) -> Vec<u8> {
+    config(slot, pins).to_frame()
+}
+
+pub fn config(slot: u8, pins: &[(u8, u16)]) -> PowerConfig {
+    PowerConfig::from_pins(slot, pins.iter().map(|(voltage, current)| PinConfig::new(*voltage, *current)).collect()).unwrap()
+}
@@ -1,6 +1,322 @@
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::Duration;
+
+use log::LevelFilter;
+use serde::Deserialize;
+
 pub const MODULE_NAME : &str = "modules.noreya.tech/io";
 pub const DRV_NAME : &str = "nexus-drv-io";
 pub const SOCKET_PATH : &str = "/run/nexus-drv-io/nexus-drv-io.socket";
 pub const POWER_MGMT_PATH : &str = "/run/power-mgmt/power-mgmt.socket";
 pub const COMPATIBLE_FW_MAJOR : u16 = 1;
-pub const COMPATIBLE_FW_MINOR : u16 = 0;
\ No newline at end of file
+pub const COMPATIBLE_FW_MINOR : u16 = 0;
+
+pub const SYSFS_ROOT : &str = "/sys/class/sdbp";
+pub const STATE_DIR : &str = "/var/lib/nexus-drv-io";
+pub const POWER_CONFIG_STATE_FILE : &str = "power-config.state";
+pub const AUDIT_DIR : &str = "/var/log/nexus-drv-io";
+pub const AUDIT_FILE : &str = "audit.log";
+
+/// Errors a retry policy can retry, see `PowerMgmtError`
+///
+/// A config rejected by the firmware is rejected again, it is never retried.
+pub const RETRYABLE_ERRORS : [&str; 3] = ["timeout", "power_mgmt_unreachable", "internal"];
+
+pub const CONFIG_PATH : &str = "/etc/nexus-drv-io/config.toml";
+pub const CONFIG_PATH_ENV : &str = "NEXUS_DRV_IO_CONFIG";
+
+static CURRENT: RwLock<Option<Arc<Settings>>> = RwLock::new(None);
+
+/// Runtime configuration, every value defaults to the constants above.
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Settings {
+    pub module_name: String,
+    pub socket_path: String,
+    pub power_mgmt_path: String,
+    pub state_dir: String,
+    /// Sysfs class directory of the SDBPK kernel driver
+    pub sysfs_root: String,
+    /// Connected slots, replaces the sysfs lookup if set
+    pub static_slots: Option<Vec<u8>>,
+    pub compatible_fw_major: u16,
+    pub compatible_fw_minor: u16,
+    /// Overrides the level of the logger (off, error, warn, info, debug, trace)
+    pub log_level: Option<String>,
+    pub timeouts: Timeouts,
+    pub retry: Retry,
+    pub audit: Audit,
+    /// Only takes effect after a restart
+    pub workers: Workers,
+}
+
+/// Retry policy per step of a power config request
+#[derive(Debug, Clone, PartialEq, Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Retry {
+    pub suspend: RetryPolicy,
+    pub test: RetryPolicy,
+    pub set: RetryPolicy,
+    pub descriptor: RetryPolicy,
+    /// Budget request, finishing a request is never retried
+    pub power_mgmt: RetryPolicy,
+}
+
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// Attempts including the first one, steps are not retried by default
+    pub max_attempts: u32,
+    /// Delay before the first retry, doubled for every further one.
+    /// A timed out module is retried after `timeouts.device_reply_ms` at the earliest.
+    pub backoff_ms: u64,
+    pub max_backoff_ms: u64,
+    /// Errors which are retried, see [`RETRYABLE_ERRORS`]
+    pub retry_on: Vec<String>,
+}
+
+/// Audit log of state-changing requests
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Audit {
+    pub dir: String,
+    /// Size in KiB after which the log is rotated
+    pub max_size_kb: u64,
+    /// Number of rotated logs kept
+    pub keep: usize,
+}
+
+/// Worker threads of the PowerMgmt device
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Workers {
+    /// Requests handled at the same time
+    pub threads: usize,
+    /// Requests waiting for a worker, further requests are refused
+    pub queue: usize,
+}
+
+/// Timeouts in ms
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Timeouts {
+    /// Reply of a module to a command
+    pub device_reply_ms: u64,
+    /// Module reconnect after a descriptor update
+    pub descriptor_wait_ms: u64,
+    /// Settle time after suspending a module
+    pub suspend_settle_ms: u64,
+    /// Reply of power-mgmt
+    pub power_mgmt_ms: u64,
+    /// Stop of a driver thread
+    pub thread_stop_ms: u64,
+    /// Stop of the UDS server, must be low
+    pub uds_stop_ms: u64,
+    /// Stop of PowerMgmt, must cover a running power config transaction
+    pub drain_ms: u64,
+    /// Duration of a request after which the watchdog considers its worker hung
+    pub request_ms: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            module_name: MODULE_NAME.to_string(),
+            socket_path: SOCKET_PATH.to_string(),
+            power_mgmt_path: POWER_MGMT_PATH.to_string(),
+            state_dir: STATE_DIR.to_string(),
+            sysfs_root: SYSFS_ROOT.to_string(),
+            static_slots: None,
+            compatible_fw_major: COMPATIBLE_FW_MAJOR,
+            compatible_fw_minor: COMPATIBLE_FW_MINOR,
+            log_level: None,
+            timeouts: Timeouts::default(),
+            retry: Retry::default(),
+            audit: Audit::default(),
+            workers: Workers::default(),
+        }
+    }
+}
+
+impl Default for RetryPolicy {
+    fn default() -> RetryPolicy {
+        RetryPolicy {
+            max_attempts: 1,
+            backoff_ms: 100,
+            max_backoff_ms: 1000,
+            retry_on: vec!["timeout".to_string(), "power_mgmt_unreachable".to_string()],
+        }
+    }
+}
+
+impl Default for Audit {
+    fn default() -> Audit {
+        Audit {
+            dir: AUDIT_DIR.to_string(),
+            max_size_kb: 1024,
+            keep: 5,
+        }
+    }
+}
+
+impl Default for Workers {
+    fn default() -> Workers {
+        Workers {
+            threads: 4,
+            queue: 16,
+        }
+    }
+}
+
+impl Default for Timeouts {
+    fn default() -> Timeouts {
+        Timeouts {
+            device_reply_ms: 1000,
+            descriptor_wait_ms: 600,
+            suspend_settle_ms: 100,
+            power_mgmt_ms: 1000,
+            thread_stop_ms: 1000,
+            uds_stop_ms: 100,
+            drain_ms: 10000,
+            request_ms: 30000,
+        }
+    }
+}
+
+impl Settings {
+    /// Reads the config file, a missing file yields the defaults unless `required` is set.
+    pub fn load(path: &Path, required: bool) -> Result<Settings, Error> {
+        let content = match fs::read_to_string(path) {
+            Ok(value) => value,
+            Err(err) if err.kind() == ErrorKind::NotFound && !required => {
+                info!("No config file at {}, using defaults", path.display());
+                return Ok(Settings::default());
+            }
+            Err(err) => return Err(Error::new(err.kind(), format!("Reading {} failed: {}", path.display(), err))),
+        };
+
+        let settings: Settings = match toml::from_str(&content) {
+            Ok(value) => value,
+            Err(err) => return Err(Error::new(ErrorKind::InvalidData, format!("Parsing {} failed: {}", path.display(), err))),
+        };
+
+        match settings.validate() {
+            Ok(_) => Ok(settings),
+            Err(err) => Err(Error::new(ErrorKind::InvalidData, format!("Invalid config {}: {}", path.display(), err))),
+        }
+    }
+
+    pub fn validate(&self) -> Result<(), String> {
+        if self.module_name.is_empty() {
+            return Err("module_name must not be empty".to_string());
+        }
+        if let Some(level) = &self.log_level {
+            if LevelFilter::from_str(level).is_err() {
+                return Err(format!("Invalid log_level: {}", level));
+            }
+        }
+        for (name, path) in [("socket_path", &self.socket_path), ("power_mgmt_path", &self.power_mgmt_path), ("state_dir", &self.state_dir), ("sysfs_root", &self.sysfs_root), ("audit.dir", &self.audit.dir)] {
+            if !Path::new(path).is_absolute() {
+                return Err(format!("{} must be an absolute path: {}", name, path));
+            }
+        }
+        let timeouts = [
+            ("device_reply_ms", self.timeouts.device_reply_ms),
+            ("descriptor_wait_ms", self.timeouts.descriptor_wait_ms),
+            ("power_mgmt_ms", self.timeouts.power_mgmt_ms),
+            ("thread_stop_ms", self.timeouts.thread_stop_ms),
+            ("uds_stop_ms", self.timeouts.uds_stop_ms),
+            ("drain_ms", self.timeouts.drain_ms),
+            ("request_ms", self.timeouts.request_ms),
+        ];
+        for (name, value) in timeouts {
+            if value == 0 {
+                return Err(format!("timeouts.{} must be greater than 0", name));
+            }
+        }
+        let policies = [
+            ("suspend", &self.retry.suspend),
+            ("test", &self.retry.test),
+            ("set", &self.retry.set),
+            ("descriptor", &self.retry.descriptor),
+            ("power_mgmt", &self.retry.power_mgmt),
+        ];
+        for (name, policy) in policies {
+            if policy.max_attempts == 0 {
+                return Err(format!("retry.{}.max_attempts must be greater than 0", name));
+            }
+            if let Some(error) = policy.retry_on.iter().find(|error| !RETRYABLE_ERRORS.contains(&error.as_str())) {
+                return Err(format!("retry.{}.retry_on: unknown error {}, expected one of {:?}", name, error, RETRYABLE_ERRORS));
+            }
+        }
+        if self.audit.max_size_kb == 0 {
+            return Err("audit.max_size_kb must be greater than 0".to_string());
+        }
+        if self.workers.threads == 0 {
+            return Err("workers.threads must be greater than 0".to_string());
+        }
+        Ok(())
+    }
+
+    pub fn log_level(&self) -> Option<LevelFilter> {
+        self.log_level.as_ref().and_then(|level| LevelFilter::from_str(level).ok())
+    }
+
+    /// Path of the config file: command line argument, then environment variable, then the default path.
+    /// The flag tells whether the path was given explicitly.
+    pub fn path(arg: Option<PathBuf>) -> (PathBuf, bool) {
+        if let Some(path) = arg {
+            return (path, true);
+        }
+        match std::env::var_os(CONFIG_PATH_ENV) {
+            Some(path) => (PathBuf::from(path), true),
+            None => (PathBuf::from(CONFIG_PATH), false),
+        }
+    }
+}
+
+impl Timeouts {
+    pub fn device_reply(&self) -> Duration {
+        Duration::from_millis(self.device_reply_ms)
+    }
+    pub fn descriptor_wait(&self) -> Duration {
+        Duration::from_millis(self.descriptor_wait_ms)
+    }
+    pub fn suspend_settle(&self) -> Duration {
+        Duration::from_millis(self.suspend_settle_ms)
+    }
+    pub fn power_mgmt(&self) -> Duration {
+        Duration::from_millis(self.power_mgmt_ms)
+    }
+    pub fn thread_stop(&self) -> Duration {
+        Duration::from_millis(self.thread_stop_ms)
+    }
+    pub fn uds_stop(&self) -> Duration {
+        Duration::from_millis(self.uds_stop_ms)
+    }
+    pub fn drain(&self) -> Duration {
+        Duration::from_millis(self.drain_ms)
+    }
+    pub fn request(&self) -> Duration {
+        Duration::from_millis(self.request_ms)
+    }
+}
+
+/// Makes the settings available to all threads.
+pub fn install(settings: Settings) {
+    let mut current = CURRENT.write().expect("Could not lock settings");
+    *current = Some(Arc::new(settings));
+}
+
+/// Returns the installed settings or the defaults if none were installed.
+pub fn current() -> Arc<Settings> {
+    let current = CURRENT.read().expect("Could not lock settings");
+    match current.as_ref() {
+        Some(settings) => settings.clone(),
+        None => Arc::new(Settings::default()),
+    }
+}
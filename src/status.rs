@@ -0,0 +1,48 @@
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use sd_notify::NotifyState;
+
+/// Set unless the driver runs with `--foreground`
+static SYSTEMD: AtomicBool = AtomicBool::new(false);
+static STATUS: Mutex<Status> = Mutex::new(Status { driver: String::new(), power_mgmt: None });
+
+/// Parts of the status reported to systemd, each part is updated by its own thread.
+struct Status {
+    driver: String,
+    power_mgmt: Option<String>,
+}
+
+/// Sends the notifications to systemd from now on.
+pub fn enable_systemd() {
+    SYSTEMD.store(true, Ordering::SeqCst);
+}
+
+/// Notifies systemd, does nothing when running in the foreground.
+pub fn notify(state: &[NotifyState]) {
+    if SYSTEMD.load(Ordering::SeqCst) {
+        let _ = sd_notify::notify(false, state);
+    }
+}
+
+/// Sets the status of the driver, the power-mgmt health is kept.
+pub fn set(driver: &str) {
+    let mut status = STATUS.lock().expect("Could not lock status");
+    status.driver = driver.to_string();
+    send(&status);
+}
+
+/// Sets the power-mgmt health, the status of the driver is kept.
+pub fn set_power_mgmt(health: &str) {
+    let mut status = STATUS.lock().expect("Could not lock status");
+    status.power_mgmt = Some(health.to_string());
+    send(&status);
+}
+
+fn send(status: &Status) {
+    let text = match &status.power_mgmt {
+        Some(health) => format!("{} (power-mgmt {})", status.driver, health),
+        None => status.driver.clone(),
+    };
+    notify(&[NotifyState::Status(&text)]);
+}
@@ -0,0 +1,214 @@
+use std::os::unix::net::UnixStream;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::thread;
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use noreya_sdbp::drv::core::*;
+use noreya_sdbp::util::*;
+use sd_notify::NotifyState;
+
+use crate::powermgmt;
+use crate::settings;
+
+/// Virtual device which probes the dispatcher and the PowerMgmt device
+pub const PROBE_VDEV_ID: u16 = 0x2002;
+/// Probe message, the PowerMgmt device sends it back unchanged
+pub const PROBE: &[u8] = b"ping";
+/// Interval of the probes and of the beats of idle threads
+pub const BEAT_INTERVAL: Duration = Duration::from_millis(1000);
+
+static REGISTRY: Mutex<Vec<Arc<Heartbeat>>> = Mutex::new(Vec::new());
+
+/// Liveness signal of a driver thread, the thread must call `beat` regularly.
+pub struct Heartbeat {
+    name: String,
+    /// Beats before and after every request only, see [`register_worker`]
+    worker: bool,
+    epoch: Instant,
+    last_ms: AtomicU64,
+}
+
+impl Heartbeat {
+    pub fn beat(&self) {
+        self.last_ms.store(self.epoch.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn age(&self) -> Duration {
+        let now = self.epoch.elapsed().as_millis() as u64;
+        Duration::from_millis(now.saturating_sub(self.last_ms.load(Ordering::Relaxed)))
+    }
+}
+
+/// Registers a thread that is supervised by the watchdog.
+pub fn register(name: &str) -> Arc<Heartbeat> {
+    register_heartbeat(name, false)
+}
+
+/// Registers a worker thread, it beats while idle and before every request.
+///
+/// A request may take a while (retries, module and power-mgmt timeouts), so a worker
+/// is only stale once a request runs longer than `timeouts.request_ms`.
+pub fn register_worker(name: &str) -> Arc<Heartbeat> {
+    register_heartbeat(name, true)
+}
+
+fn register_heartbeat(name: &str, worker: bool) -> Arc<Heartbeat> {
+    let heartbeat = Arc::new(Heartbeat { name: name.to_string(), worker, epoch: Instant::now(), last_ms: AtomicU64::new(0) });
+    REGISTRY.lock().expect("Could not lock watchdog registry").push(heartbeat.clone());
+    heartbeat
+}
+
+/// Removes the heartbeat of a thread that stopped.
+pub fn unregister(heartbeat: &Arc<Heartbeat>) {
+    REGISTRY.lock().expect("Could not lock watchdog registry").retain(|registered| !Arc::ptr_eq(registered, heartbeat));
+}
+
+/// Names of the threads whose heartbeat is older than allowed.
+fn stale(timeout: Duration) -> Vec<String> {
+    let request = settings::current().timeouts.request();
+    REGISTRY.lock().expect("Could not lock watchdog registry").iter()
+        .filter(|heartbeat| heartbeat.age() > if heartbeat.worker { request } else { timeout / 2 })
+        .map(|heartbeat| heartbeat.name.clone())
+        .collect()
+}
+
+/// Runs the virtual device [`PROBE_VDEV_ID`]: sends [`PROBE`] to the PowerMgmt device through the dispatcher.
+///
+/// The answer proves that the dispatcher and the PowerMgmt thread route messages, each one beats the
+/// "Dispatcher" heartbeat. The Controller, DeviceHandler and UdsServer threads of the SDBP library take
+/// no messages from a virtual device, see [`Watchdog`] for what is checked of them.
+pub fn probe_function(vdev_id: u16, ctl_pair: ChannelPair<ManagedThreadState>, dev_pair: ChannelPair<PMsg>, _shared: SharedStats) {
+    let mut stopped = false;
+    let heartbeat = register("Dispatcher");
+    let mut next_probe = Instant::now();
+
+    while !stopped {
+        ManagedThreadUtil::is_stopped(&mut stopped, &ctl_pair);
+        if Instant::now() >= next_probe {
+            next_probe = Instant::now() + BEAT_INTERVAL;
+            match dev_pair.tx().send(PMsg::create(vdev_id, powermgmt::VDEV_ID, Ok(PROBE.to_vec()))) {
+                Ok(_) => (),
+                Err(err) => error!("Watchdog probe failed: {}", err),
+            }
+        }
+        match dev_pair.rx().recv_timeout(Duration::from_millis(150)) {
+            Ok(msg) if msg.get_src() == powermgmt::VDEV_ID => heartbeat.beat(),
+            Ok(msg) => warn!("Watchdog probe: discarding message from {}", msg.get_src()),
+            Err(_err) => (),
+        }
+    }
+    unregister(&heartbeat);
+}
+
+/// Checks that the UdsServer accepts clients on the driver socket.
+///
+/// The connection is closed right away. Once the server stops accepting, the backlog fills up
+/// and connecting blocks, so the supervisor stops pinging as well.
+fn uds_server_accepts(path: &str) -> bool {
+    match UnixStream::connect(path) {
+        Ok(_) => true,
+        Err(err) => {
+            debug!("Watchdog: connecting to {} failed: {}", path, err);
+            false
+        }
+    }
+}
+
+/// Pings the systemd watchdog while all supervised threads are responsive.
+pub struct Watchdog {
+    stopped: Arc<AtomicBool>,
+    thread: JoinHandle<()>,
+}
+
+impl Watchdog {
+    /// Starts the supervisor if systemd expects watchdog pings (`WatchdogSec`).
+    ///
+    /// Threads with a heartbeat are stale after half the watchdog timeout, workers once their request
+    /// exceeds `timeouts.request_ms`. The dispatcher is probed by [`probe_function`], the UdsServer by
+    /// connecting to the driver socket.
+    ///
+    /// The Controller and DeviceHandler are not confirmed responsive: the SDBP library offers no request
+    /// they answer to the driver. The supervisor only locks the shared stats they use, so it stops pinging
+    /// if one of them hangs while holding the lock, but not if one hangs elsewhere.
+    pub fn start(mut shared: SharedStats) -> Option<Watchdog> {
+        let mut usec = 0;
+        if !sd_notify::watchdog_enabled(false, &mut usec) {
+            return None;
+        }
+        let timeout = Duration::from_micros(usec);
+        info!("Watchdog enabled with timeout {:?}", timeout);
+
+        let stopped = Arc::new(AtomicBool::new(false));
+        let thread_stopped = stopped.clone();
+        let thread = thread::Builder::new().name("watchdog".to_string()).spawn(move || {
+            let mut reported = false;
+            while !thread_stopped.load(Ordering::Relaxed) {
+                thread::sleep(timeout / 4);
+
+                drop(shared.read());
+
+                let mut stale = stale(timeout);
+                if !uds_server_accepts(&settings::current().socket_path) {
+                    stale.push("UdsServer".to_string());
+                }
+
+                if stale.is_empty() {
+                    let _ = sd_notify::notify(false, &[NotifyState::Watchdog]);
+                    reported = false;
+                } else if !reported {
+                    error!("Watchdog: {} not responding", stale.join(", "));
+                    reported = true;
+                }
+            }
+        }).expect("Could not spawn watchdog thread");
+
+        Some(Watchdog { stopped, thread })
+    }
+
+    pub fn stop(self) {
+        self.stopped.store(true, Ordering::Relaxed);
+        let _ = self.thread.join();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::os::unix::net::UnixListener;
+    use std::thread;
+
+    use tempfile::TempDir;
+
+    use super::*;
+    use crate::powermgmt::install_settings;
+
+    #[test]
+    fn worker_is_stale_once_its_request_exceeds_the_timeout() {
+        install_settings();
+        let timeout = Duration::from_secs(3600);
+        let worker = register_worker("stuck worker");
+        let thread = register("stuck thread");
+
+        assert!(!stale(timeout).contains(&"stuck worker".to_string()));
+        thread::sleep(settings::current().timeouts.request() + Duration::from_millis(10));
+        assert!(stale(timeout).contains(&"stuck worker".to_string()));
+        assert!(!stale(timeout).contains(&"stuck thread".to_string()));
+
+        worker.beat();
+        assert!(!stale(timeout).contains(&"stuck worker".to_string()));
+        unregister(&worker);
+        unregister(&thread);
+    }
+
+    #[test]
+    fn uds_server_is_stale_once_the_socket_stops_accepting() {
+        let dir = TempDir::new().unwrap();
+        let path = dir.path().join("driver.sock");
+        let listener = UnixListener::bind(&path).unwrap();
+        assert!(uds_server_accepts(path.to_str().unwrap()));
+
+        drop(listener);
+        assert!(!uds_server_accepts(path.to_str().unwrap()));
+    }
+}